@@ -23,7 +23,7 @@ async fn main() {
             let app_secret_key = SecretKey::from_bech32(APP_SECRET_KEY);
             let app_keys = Keys::new(app_secret_key.expect("REASON"));
             let processor = Processor::new();
-            let mut relay_manager = RelayManager::new(app_keys, processor);
+            let mut relay_manager = RelayManager::new(app_keys, processor).with_git_args(args);
             let _ = relay_manager
                 .run(vec![BOOTSTRAP_RELAY1, BOOTSTRAP_RELAY2, BOOTSTRAP_RELAY3])
                 .await;
@@ -257,7 +257,7 @@ fn run(args: &Args) -> Result<(), Error> {
                 true
             })?;
         }
-        println!("");
+        println!();
     }
 
     Ok(())
@@ -281,6 +281,7 @@ fn log_message_matches(msg: Option<&str>, grep: &Option<String>) -> bool {
     }
 }
 
+#[allow(dead_code)]
 fn padded_commit_id(commit: &Commit) -> String {
     format!("{:0>64}", commit.id())
 }
@@ -0,0 +1,145 @@
+//! Detection of relays serving the same events, e.g. mirrors
+use nostr_sdk::prelude::{EventId, Url};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+/// Number of hash functions in a [`MinHash`] signature; the similarity
+/// estimate is within about 1/sqrt(SIGNATURE_LEN) of the true value
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Default similarity above which two relays are reported as clones
+pub const DEFAULT_CLONE_THRESHOLD: f64 = 0.9;
+
+/// Relays with fewer events than this are not compared, a handful of
+/// events says nothing about the whole set
+pub const MIN_CLONE_EVENTS: u64 = 50;
+
+/// MinHash signature of a set of event ids: a fixed size digest from which
+/// the Jaccard similarity of two sets can be estimated, without keeping the
+/// ids themselves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinHash {
+    mins: [u64; SIGNATURE_LEN],
+    /// Number of ids added, including repeated ones
+    count: u64,
+}
+
+impl Default for MinHash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MinHash {
+    pub fn new() -> Self {
+        Self {
+            mins: [u64::MAX; SIGNATURE_LEN],
+            count: 0,
+        }
+    }
+
+    pub fn add(&mut self, id: &EventId) {
+        self.count += 1;
+        for (seed, min) in self.mins.iter_mut().enumerate() {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            id.hash(&mut hasher);
+            *min = (*min).min(hasher.finish());
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Estimated Jaccard similarity of the two sets, from 0.0 to 1.0
+    pub fn similarity(&self, other: &MinHash) -> f64 {
+        let equal = self
+            .mins
+            .iter()
+            .zip(other.mins.iter())
+            .filter(|(a, b)| a == b)
+            .count();
+        equal as f64 / SIGNATURE_LEN as f64
+    }
+}
+
+/// Groups of relays whose event sets have a similarity of at least
+/// `threshold`, directly or through other relays of the group. Each group
+/// is sorted, and the groups by their first relay.
+pub fn clusters(signatures: &HashMap<Url, MinHash>, threshold: f64) -> Vec<Vec<Url>> {
+    let mut urls: Vec<&Url> = signatures
+        .iter()
+        .filter(|(_, s)| s.count() >= MIN_CLONE_EVENTS)
+        .map(|(u, _)| u)
+        .collect();
+    urls.sort();
+    // Union-find over the indices of urls
+    let mut parent: Vec<usize> = (0..urls.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for i in 0..urls.len() {
+        for j in i + 1..urls.len() {
+            if signatures[urls[i]].similarity(&signatures[urls[j]]) >= threshold {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+    }
+    let mut groups: HashMap<usize, Vec<Url>> = HashMap::new();
+    for (i, url) in urls.iter().enumerate() {
+        let r = root(&mut parent, i);
+        groups.entry(r).or_default().push((*url).clone());
+    }
+    let mut res: Vec<Vec<Url>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    res.sort();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(i: u64) -> EventId {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&i.to_be_bytes());
+        EventId::from_slice(&bytes).unwrap()
+    }
+
+    fn signature(ids: std::ops::Range<u64>) -> MinHash {
+        let mut s = MinHash::new();
+        for i in ids {
+            s.add(&id(i));
+        }
+        s
+    }
+
+    #[test]
+    fn clusters_identical_sets_only() {
+        let mut signatures = HashMap::new();
+        let a = Url::parse("wss://a.example.com").unwrap();
+        let b = Url::parse("wss://b.example.com").unwrap();
+        let c = Url::parse("wss://c.example.com").unwrap();
+        signatures.insert(a.clone(), signature(0..200));
+        signatures.insert(b.clone(), signature(0..200));
+        signatures.insert(c, signature(1000..1200));
+        assert_eq!(
+            clusters(&signatures, DEFAULT_CLONE_THRESHOLD),
+            vec![vec![a, b]]
+        );
+    }
+
+    #[test]
+    fn similarity_estimates_jaccard() {
+        // |A ∩ B| / |A ∪ B| = 500 / 1500
+        let a = signature(0..1000);
+        let b = signature(500..1500);
+        assert!((a.similarity(&b) - 1.0 / 3.0).abs() < 0.2);
+    }
+}
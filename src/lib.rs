@@ -1,7 +1,10 @@
+pub mod clones;
 pub mod processor;
 pub mod pubkeys;
+pub mod relay_info;
 pub mod relay_manager;
 pub mod relays;
+pub mod reputation;
 pub mod stats;
 
 use clap::Parser;
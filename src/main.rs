@@ -3,16 +3,278 @@ use nostr_relays::processor::APP_SECRET_KEY;
 use nostr_relays::processor::BOOTSTRAP_RELAY1;
 use nostr_relays::processor::BOOTSTRAP_RELAY2;
 use nostr_relays::processor::BOOTSTRAP_RELAY3;
-use nostr_relays::relay_manager::RelayManager;
-use nostr_sdk::prelude::{FromBech32, Keys, Result, SecretKey};
+use nostr_relays::relay_info::InfoCache;
+use nostr_relays::relay_manager::{RelayManager, DEFAULT_MAX_LOOKBACK, DEFAULT_STALE_AFTER};
+use nostr_relays::relays::{canonical_url, RelaysDump};
+use nostr_relays::reputation::ReputationStore;
+use nostr_relays::CliArgs;
+use nostr_sdk::prelude::{Event, FromBech32, Keys, RelayOptions, Result, SecretKey, Url};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
 
 use env_logger::Env;
 use log::log_enabled;
 use log::Level;
 use log::{debug, error, info, trace, warn};
 
+#[derive(Subcommand)]
+enum Command {
+    /// Diagnose a single relay: connect to it, measuring how long it takes,
+    /// fetch its NIP-11 document, print the result as JSON and exit
+    Probe {
+        /// URL of the relay
+        url: Url,
+    },
+    /// Compare two saved relay dumps: print the relays added, removed and
+    /// unchanged, the reference count changes and the newly stale relays
+    Diff {
+        /// dump of the older run
+        old: PathBuf,
+        /// dump of the newer run
+        new: PathBuf,
+        /// print the difference as JSON
+        #[arg(long)]
+        json: bool,
+        /// relays not referenced in this many days before their dump are stale (default: 30)
+        #[arg(long)]
+        stale_after_days: Option<u64>,
+    },
+}
+
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[structopt(name = "limit", long)]
+    /// maximum number of events each relay should return per filter
+    flag_limit: Option<usize>,
+    #[structopt(name = "max-filter-kinds", long)]
+    /// maximum number of kinds in one subscription filter (default: 10)
+    flag_max_filter_kinds: Option<usize>,
+    #[structopt(name = "tail", long)]
+    /// follow live events, discovering relays until interrupted
+    flag_tail: bool,
+    #[structopt(name = "relay-list", long)]
+    /// file with a NIP-65 relay list event (JSON) to bootstrap from
+    flag_relay_list: Option<String>,
+    #[structopt(name = "allowlist", long)]
+    /// file with relay URLs, one per line; only these relays are crawled
+    flag_allowlist: Option<String>,
+    #[structopt(name = "key-file", long)]
+    /// file with the secret key (nsec) to connect with
+    flag_key_file: Option<String>,
+    #[structopt(name = "pretty", long)]
+    /// print the relays found as a sorted list instead of JSON
+    flag_pretty: bool,
+    #[structopt(name = "productive-only", long)]
+    /// print only the relays that sent events
+    flag_productive_only: bool,
+    #[structopt(name = "min-events", long)]
+    /// while the crawl stops with fewer events, double the 6 hour lookback and crawl the added part
+    flag_min_events: Option<u64>,
+    #[structopt(name = "max-lookback", long)]
+    /// with --min-events, widen the lookback up to this many seconds (default: 604800)
+    flag_max_lookback: Option<u64>,
+    #[structopt(name = "max-runtime", long)]
+    /// stop crawling after this many seconds
+    flag_max_runtime: Option<u64>,
+    #[structopt(name = "nip66-relay", long)]
+    /// after the crawl, publish NIP-66 relay discovery events for the relays connected to this relay
+    flag_nip66_relay: Option<Url>,
+    #[structopt(name = "reputation", long)]
+    /// keep relay reputations in this file, and start from the relays with the best reputation
+    flag_reputation: Option<PathBuf>,
+    #[structopt(name = "discovery-curve", long)]
+    /// write the number of relays discovered over time to this file, as CSV
+    flag_discovery_curve: Option<PathBuf>,
+    #[structopt(name = "npubs", long)]
+    /// write the pubkeys seen to this file, one npub per line
+    flag_npubs: Option<PathBuf>,
+    #[structopt(name = "dump-dir", long)]
+    /// also save the relay dump to this directory as relays-<timestamp>.json, keeping the newest ones
+    flag_dump_dir: Option<PathBuf>,
+    #[structopt(name = "keep-dumps", long)]
+    /// number of dumps kept in the dump directory, older ones are deleted (default: 10)
+    flag_keep_dumps: Option<usize>,
+    #[structopt(name = "change-log", long)]
+    /// write every relay addition and removal, with its reason, to this file, as JSON
+    flag_change_log: Option<PathBuf>,
+    #[structopt(name = "timeline", long)]
+    /// write the timeline of relay connections, EOSEs and disconnections to this file, as JSON
+    flag_timeline: Option<PathBuf>,
+    #[structopt(name = "warm-start", long)]
+    /// connect first to the best relays in this dump of a previous run, if the file exists
+    flag_warm_start: Option<PathBuf>,
+    #[structopt(name = "graph", long)]
+    /// write the relay discovery graph to this file, in Graphviz DOT format
+    flag_graph: Option<PathBuf>,
+    #[structopt(name = "nip11-cache", long)]
+    /// keep fetched NIP-11 documents in this file, and reuse them across runs
+    flag_nip11_cache: Option<PathBuf>,
+    #[structopt(name = "nip11-cache-ttl", long)]
+    /// reuse cached NIP-11 documents for this many seconds (default: 86400)
+    flag_nip11_cache_ttl: Option<u64>,
+    #[structopt(name = "nip11", long)]
+    /// fetch the NIP-11 relay information of the relays found
+    flag_nip11: bool,
+    #[structopt(name = "nip11-survey", long)]
+    /// don't crawl, only connect to the known relays in batches and fetch their NIP-11 documents
+    flag_nip11_survey: bool,
+    #[structopt(name = "include-raw-nip11", long)]
+    /// with --nip11, also include each NIP-11 document in the output as served
+    flag_include_raw_nip11: bool,
+    #[structopt(name = "resolve-ips", long)]
+    /// resolve the relay hosts after the crawl, and report the relays sharing an address
+    flag_resolve_ips: bool,
+    #[structopt(name = "check", long)]
+    /// only check which relays in this file (one URL per line) are reachable
+    flag_check: Option<String>,
+    #[structopt(name = "validate", long)]
+    /// only validate the relays in this JSON Lines file (one URL string, or
+    /// object with a `url`, per line), printing one JSON result per line
+    flag_validate: Option<String>,
+    #[structopt(name = "discovery-idle", long)]
+    /// stop when no new relay has been discovered for this many seconds
+    flag_discovery_idle: Option<u64>,
+    #[structopt(name = "clone-threshold", long)]
+    /// report relays whose event sets are at least this similar (0-1) as clones
+    flag_clone_threshold: Option<f64>,
+    #[structopt(name = "require-nips", long, value_delimiter = ',')]
+    /// only connect to relays listing all these NIPs (comma separated) in their NIP-11 document
+    flag_require_nips: Vec<u16>,
+    #[structopt(name = "forbid-nips", long, value_delimiter = ',')]
+    /// don't connect to relays listing any of these NIPs (comma separated) in their NIP-11 document
+    flag_forbid_nips: Vec<u16>,
+    #[structopt(name = "hosts", long, value_delimiter = ',')]
+    /// only connect to relays whose host matches one of these patterns (comma separated, `*` as wildcard)
+    flag_hosts: Vec<String>,
+    #[structopt(name = "metadata", long)]
+    /// also discover relays from user metadata, resolving NIP-05 identifiers
+    flag_metadata: bool,
+    #[structopt(name = "search", long)]
+    /// also send this NIP-50 search query to the relays supporting it, and report the relays matching it
+    flag_search: Option<String>,
+    #[structopt(name = "no-eose-window", long)]
+    /// report relays serving events but no EOSE within this many seconds as no-EOSE (default: 60)
+    flag_no_eose_window: Option<u64>,
+    #[structopt(name = "exclude-no-eose", long)]
+    /// don't wait for EOSE from no-EOSE relays before stopping
+    flag_exclude_no_eose: bool,
+    #[structopt(name = "notification-buffer", long)]
+    /// buffer this many relay notifications on top of the 1024 of nostr-sdk, for high event volumes
+    flag_notification_buffer: Option<usize>,
+    #[structopt(name = "max-pending", long)]
+    /// queue at most this many discovered relays to be dialed first, dropping the oldest (default: 4096)
+    flag_max_pending: Option<usize>,
+    #[structopt(name = "full-sweep", long)]
+    /// after the crawl, crawl the relays not connected to yet in batches, until all were
+    flag_full_sweep: bool,
+    #[structopt(name = "allow-placeholders", long)]
+    /// accept placeholder relay URLs such as ws://localhost or wss://relay, e.g. to crawl a local relay
+    flag_allow_placeholders: bool,
+    #[structopt(name = "pinned", long)]
+    /// only connect to the seed and bootstrap relays, discovered relays are recorded only
+    flag_pinned: bool,
+    #[structopt(name = "relays-only", long)]
+    /// only subscribe to the kinds carrying relay URLs, and only extract the relays
+    flag_relays_only: bool,
+    #[structopt(name = "max-event-rate", long)]
+    /// accept at most this many events per second from each relay, dropping the excess
+    flag_max_event_rate: Option<u64>,
+    #[structopt(name = "max-event-size", long)]
+    /// drop events larger than this many bytes of JSON
+    flag_max_event_size: Option<usize>,
+    #[structopt(name = "rate-limit-cooldown", long)]
+    /// don't dial a relay rate limiting us for this many seconds, unless it suggests a delay (default: 60)
+    flag_rate_limit_cooldown: Option<u64>,
+    #[structopt(name = "streaming", long)]
+    /// keep only the relays and counters in memory, not per-event state
+    flag_streaming: bool,
+    #[structopt(name = "proxy", long)]
+    /// SOCKS5 proxy (host:port) to connect to relays through
+    flag_proxy: Option<SocketAddr>,
+    #[structopt(name = "proxy-onion-only", long)]
+    /// connect only to onion relays through the proxy, to the others directly
+    flag_proxy_onion_only: bool,
+    #[command(flatten)]
+    git: CliArgs,
+}
+
+/// Environment variable with the secret key (nsec) to connect with
+const APP_SECRET_ENV: &str = "NOSTR_APP_SECRET";
+
+/// Default for `--nip11-cache-ttl`, one day
+const DEFAULT_NIP11_CACHE_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Default for `--keep-dumps`
+const DEFAULT_KEEP_DUMPS: usize = 10;
+
+/// Load the app keys from the key file if given, else from the environment,
+/// falling back to the built-in key
+fn load_app_keys(key_file: Option<&str>) -> Result<Keys> {
+    let (source, nsec) = if let Some(path) = key_file {
+        (path.to_string(), std::fs::read_to_string(path)?)
+    } else if let Ok(nsec) = std::env::var(APP_SECRET_ENV) {
+        (APP_SECRET_ENV.to_string(), nsec)
+    } else {
+        return Ok(Keys::new(SecretKey::from_bech32(APP_SECRET_KEY)?));
+    };
+    let secret_key = SecretKey::from_bech32(nsec.trim())
+        .map_err(|e| format!("{source}: invalid secret key, expected nsec: {e}"))?;
+    Ok(Keys::new(secret_key))
+}
+
+/// Read relay URLs from a JSON Lines file, each line either a URL string or
+/// an object with a `url` field
+fn read_relay_urls_jsonl(path: &str) -> Result<Vec<Url>> {
+    let mut urls = Vec::new();
+    for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: serde_json::Value = serde_json::from_str(line)
+            .map_err(|e| format!("{path}:{}: invalid JSON: {e}", i + 1))?;
+        let url = match &value {
+            serde_json::Value::String(url) => Some(url.as_str()),
+            serde_json::Value::Object(obj) => obj.get("url").and_then(|u| u.as_str()),
+            _ => None,
+        }
+        .ok_or_else(|| {
+            format!(
+                "{path}:{}: expected a URL string or {{\"url\": ...}}",
+                i + 1
+            )
+        })?;
+        let url = Url::parse(url)
+            .map_err(|e| format!("{path}:{}: invalid relay URL {url}: {e}", i + 1))?;
+        urls.push(url);
+    }
+    Ok(urls)
+}
+
+/// Read relay URLs, one per line, skipping empty lines and `#` comments
+fn read_relay_urls(path: &str) -> Result<HashSet<Url>> {
+    let mut urls = HashSet::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let url = Url::parse(line).map_err(|e| format!("{path}: invalid relay URL {line}: {e}"))?;
+        urls.insert(url);
+    }
+    Ok(urls)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
     let env = Env::default()
         .filter_or("MY_LOG_LEVEL", "none")
         .write_style_or("MY_LOG_STYLE", "always");
@@ -32,13 +294,193 @@ async fn main() -> Result<()> {
         let x = 3 * 4; // expensive computation
         info!("the answer was: {}", x);
     }
-    let app_secret_key = SecretKey::from_bech32(APP_SECRET_KEY)?;
-    let app_keys = Keys::new(app_secret_key);
+    if let Some(Command::Diff {
+        old,
+        new,
+        json,
+        stale_after_days,
+    }) = &args.command
+    {
+        let stale_after = stale_after_days
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+            .unwrap_or(DEFAULT_STALE_AFTER);
+        let diff = RelaysDump::load(old)?.diff(&RelaysDump::load(new)?, stale_after);
+        if *json {
+            println!("{}", serde_json::to_string_pretty(&diff)?);
+        } else {
+            diff.print();
+        }
+        return Ok(());
+    }
+    let app_keys = load_app_keys(args.flag_key_file.as_deref())?;
+    if let Some(Command::Probe { url }) = &args.command {
+        let relay_manager = RelayManager::new(app_keys, Processor::new());
+        let probe = relay_manager.probe_relay(url).await;
+        println!("{}", serde_json::to_string_pretty(&probe)?);
+        return Ok(());
+    }
     let processor = Processor::new();
     let mut relay_manager = RelayManager::new(app_keys, processor);
+    if let Some(path) = args.flag_change_log {
+        relay_manager = relay_manager.with_change_log_output(path);
+    }
+    relay_manager = relay_manager
+        .with_placeholders_allowed(args.flag_allow_placeholders)
+        .with_per_relay_limit(args.flag_limit)
+        .with_tail(args.flag_tail)
+        .with_pretty_dump(args.flag_pretty)
+        .with_productive_only(args.flag_productive_only)
+        .with_relay_info(args.flag_nip11)
+        .with_raw_relay_info(args.flag_include_raw_nip11)
+        .with_info_survey(args.flag_nip11_survey)
+        .with_addr_resolution(args.flag_resolve_ips)
+        .with_streaming(args.flag_streaming)
+        .relays_only(args.flag_relays_only)
+        .with_discovery_expands_pool(!args.flag_pinned)
+        .with_full_sweep(args.flag_full_sweep)
+        .with_no_eose_excluded(args.flag_exclude_no_eose)
+        .with_metadata_discovery(args.flag_metadata)
+        .require_nips(args.flag_require_nips)
+        .forbid_nips(args.flag_forbid_nips)
+        .with_host_patterns(args.flag_hosts)
+        .with_git_args(args.git);
+    if let Some(path) = &args.flag_validate {
+        let urls = read_relay_urls_jsonl(path)?;
+        for validation in relay_manager.validate_relays(urls).await {
+            println!("{}", serde_json::to_string(&validation)?);
+        }
+        return Ok(());
+    }
+    if let Some(path) = &args.flag_check {
+        let urls: Vec<String> = read_relay_urls(path)?
+            .iter()
+            .map(|u| u.to_string())
+            .collect();
+        let urls: Vec<&str> = urls.iter().map(|u| &u[..]).collect();
+        relay_manager.add_relays(&urls);
+        let mut results = relay_manager.check_connectivity().await;
+        results.sort();
+        for (url, reachable) in results {
+            let status = if reachable {
+                "reachable"
+            } else {
+                "unreachable"
+            };
+            println!("{} {status}", canonical_url(&url));
+        }
+        return Ok(());
+    }
+    if let Some(proxy) = args.flag_proxy {
+        relay_manager = relay_manager.with_proxy(proxy);
+        if args.flag_proxy_onion_only {
+            relay_manager = relay_manager.with_relay_options(Arc::new(move |url, _| {
+                let onion = url.host_str().is_some_and(|h| h.ends_with(".onion"));
+                (onion.then_some(proxy), RelayOptions::default())
+            }));
+        }
+    }
+    if let Some(secs) = args.flag_rate_limit_cooldown {
+        relay_manager = relay_manager.with_rate_limit_cooldown(Duration::from_secs(secs));
+    }
+    if let Some(relay) = args.flag_nip66_relay {
+        relay_manager = relay_manager.with_monitor_relay(relay);
+    }
+    if let Some(search) = &args.flag_search {
+        relay_manager = relay_manager.with_search(search.clone());
+    }
+    if let Some(secs) = args.flag_no_eose_window {
+        relay_manager = relay_manager.with_no_eose_window(Duration::from_secs(secs));
+    }
+    if let Some(size) = args.flag_notification_buffer {
+        relay_manager = relay_manager.with_notification_buffer(size);
+    }
+    if let Some(max) = args.flag_max_pending {
+        relay_manager = relay_manager.with_max_pending(max);
+    }
+    if let Some(size) = args.flag_max_event_size {
+        relay_manager = relay_manager.with_max_event_size(size);
+    }
+    if let Some(rate) = args.flag_max_event_rate {
+        relay_manager = relay_manager.with_max_event_rate(rate);
+    }
+    if let Some(max) = args.flag_max_filter_kinds {
+        relay_manager = relay_manager.with_max_filter_kinds(max);
+    }
+    if let Some(threshold) = args.flag_clone_threshold {
+        relay_manager = relay_manager.with_clone_threshold(threshold);
+    }
+    if let Some(secs) = args.flag_discovery_idle {
+        relay_manager = relay_manager.cancel_after_discovery_idle(Duration::from_secs(secs));
+    }
+    if let Some(min_events) = args.flag_min_events {
+        let max_lookback = args
+            .flag_max_lookback
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_MAX_LOOKBACK);
+        relay_manager = relay_manager.with_adaptive_lookback(min_events, max_lookback);
+    }
+    if let Some(secs) = args.flag_max_runtime {
+        relay_manager = relay_manager.with_max_runtime(Duration::from_secs(secs));
+    }
+    if let Some(path) = &args.flag_warm_start {
+        if path.exists() {
+            let dump = RelaysDump::load(path)?;
+            relay_manager = relay_manager.with_warm_start(&dump);
+        } else {
+            debug!("No previous run dump at {}, starting cold", path.display());
+        }
+    }
+    if let Some(path) = &args.flag_nip11_cache {
+        let ttl = args
+            .flag_nip11_cache_ttl
+            .unwrap_or(DEFAULT_NIP11_CACHE_TTL_SECS);
+        relay_manager = relay_manager.with_nip11_cache_ttl(Duration::from_secs(ttl));
+        if path.exists() {
+            relay_manager = relay_manager.with_nip11_cache(InfoCache::load(path)?);
+        }
+    } else if let Some(ttl) = args.flag_nip11_cache_ttl {
+        relay_manager = relay_manager.with_nip11_cache_ttl(Duration::from_secs(ttl));
+    }
+    if let Some(path) = &args.flag_reputation {
+        let store = if path.exists() {
+            ReputationStore::load(path)?
+        } else {
+            ReputationStore::default()
+        };
+        relay_manager = relay_manager.with_reputation(store);
+    }
+    if let Some(dir) = args.flag_dump_dir {
+        let keep = args.flag_keep_dumps.unwrap_or(DEFAULT_KEEP_DUMPS);
+        relay_manager = relay_manager.with_dump_rotation(dir, keep);
+    }
+    if let Some(path) = args.flag_npubs {
+        relay_manager = relay_manager.with_npubs_output(path);
+    }
+    if let Some(path) = args.flag_discovery_curve {
+        relay_manager = relay_manager.with_discovery_curve_output(path);
+    }
+    if let Some(path) = args.flag_timeline {
+        relay_manager = relay_manager.with_timeline_output(path);
+    }
+    if let Some(path) = args.flag_graph {
+        relay_manager = relay_manager.with_graph_output(path);
+    }
+    if let Some(path) = &args.flag_allowlist {
+        relay_manager = relay_manager.with_allowlist(read_relay_urls(path)?);
+    }
+    if let Some(path) = &args.flag_relay_list {
+        let event = Event::from_json(std::fs::read_to_string(path)?)?;
+        relay_manager.bootstrap_from_relay_list(&event)?;
+    }
     relay_manager
         .run(vec![BOOTSTRAP_RELAY1, BOOTSTRAP_RELAY2, BOOTSTRAP_RELAY3])
         .await?;
+    if let Some(path) = &args.flag_nip11_cache {
+        relay_manager.nip11_cache().save(path)?;
+    }
+    if let (Some(path), Some(store)) = (&args.flag_reputation, relay_manager.reputation()) {
+        store.save(path)?;
+    }
     //relay_manager.processor.dump();
 
     Ok(())
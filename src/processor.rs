@@ -1,6 +1,5 @@
 use crate::pubkeys::PubKeys;
 use crate::stats::Stats;
-use log::{debug, info};
 use nostr_sdk::prelude::{Event, Kind, Tag, Timestamp};
 
 pub const BOOTSTRAP_RELAY1: &str = "wss://nos.lol";
@@ -32,7 +31,13 @@ impl Processor {
         Timestamp::now().as_i64() - t.as_i64()
     }
 
+    /// The keys seen in the events handled
+    pub fn pubkeys(&self) -> &PubKeys {
+        &self.pubkeys
+    }
+
     pub fn handle_event(&mut self, event: &Event) {
+        self.pubkeys.add_author(&event.pubkey);
         //TODO: forward (proxy)
         //println!("{:?}", event.id);
         //println!("{:}", event.as_json());
@@ -122,7 +127,7 @@ impl Processor {
                 //let mut cnt = 0;
                 for t in &event.tags {
                     if let Tag::PubKey(pk, _s) = t {
-                        self.pubkeys.add(pk);
+                        self.pubkeys.add_referenced(pk);
                         //cnt += 1;
                     }
                 }
@@ -161,3 +166,14 @@ impl Processor {
         //self.pubkeys.dump();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Processor;
+
+    #[test]
+    fn default_constructs_empty() {
+        let processor = Processor::default();
+        assert_eq!(processor.pubkeys().count(), 0);
+    }
+}
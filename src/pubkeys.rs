@@ -1,8 +1,12 @@
 use nostr_sdk::prelude::{FromBech32, ToBech32, XOnlyPublicKey};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub struct PubKeys {
     p: HashMap<XOnlyPublicKey, u64>,
+    /// Keys that signed events
+    authors: HashSet<XOnlyPublicKey>,
+    /// Keys referenced in `p` tags
+    referenced: HashSet<XOnlyPublicKey>,
 }
 
 impl Default for PubKeys {
@@ -15,6 +19,8 @@ impl PubKeys {
     pub fn new() -> Self {
         Self {
             p: HashMap::default(),
+            authors: HashSet::default(),
+            referenced: HashSet::default(),
         }
     }
 
@@ -29,6 +35,28 @@ impl PubKeys {
         self.p[p]
     }
 
+    /// Add the signer of an event
+    pub fn add_author(&mut self, p: &XOnlyPublicKey) -> u64 {
+        self.authors.insert(*p);
+        self.add(p)
+    }
+
+    /// Add a key referenced in a `p` tag
+    pub fn add_referenced(&mut self, p: &XOnlyPublicKey) -> u64 {
+        self.referenced.insert(*p);
+        self.add(p)
+    }
+
+    /// Number of distinct keys that signed events
+    pub fn author_count(&self) -> usize {
+        self.authors.len()
+    }
+
+    /// Number of distinct keys referenced in `p` tags
+    pub fn referenced_count(&self) -> usize {
+        self.referenced.len()
+    }
+
     #[allow(dead_code)]
     pub fn add_str(&mut self, p: &str) -> u64 {
         let pp = match XOnlyPublicKey::from_bech32(p) {
@@ -38,9 +66,17 @@ impl PubKeys {
         self.add(&pp)
     }
 
-    // pub fn count(&self) -> usize {
-    //     self.p.len()
-    // }
+    /// Number of distinct keys, authors and referenced
+    pub fn count(&self) -> usize {
+        self.p.len()
+    }
+
+    /// All keys as bech32 `npub`s, sorted
+    pub fn dump_npubs(&self) -> Vec<String> {
+        let mut npubs: Vec<String> = self.p.keys().filter_map(|pk| pk.to_bech32().ok()).collect();
+        npubs.sort();
+        npubs
+    }
 
     pub fn dump(&self) {
         println!("Found  {}  public keys:", self.p.len());
@@ -50,3 +86,50 @@ impl PubKeys {
         println!();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PubKeys;
+    use nostr_sdk::prelude::{Keys, XOnlyPublicKey};
+    use std::str::FromStr;
+
+    #[test]
+    fn default_constructs_empty() {
+        let pubkeys = PubKeys::default();
+        assert_eq!(pubkeys.count(), 0);
+        assert_eq!(pubkeys.author_count(), 0);
+        assert_eq!(pubkeys.referenced_count(), 0);
+    }
+
+    #[test]
+    fn dump_npubs_encodes_as_bech32() {
+        // The NIP-19 example key
+        let pk = XOnlyPublicKey::from_str(
+            "7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e",
+        )
+        .unwrap();
+        let mut pubkeys = PubKeys::new();
+        pubkeys.add_author(&pk);
+        let npubs = pubkeys.dump_npubs();
+        assert_eq!(
+            npubs,
+            vec!["npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg"]
+        );
+        let mut decoded = PubKeys::new();
+        assert_eq!(decoded.add_str(&npubs[0]), 0);
+        assert_eq!(decoded.dump_npubs(), npubs);
+    }
+
+    #[test]
+    fn authors_and_referenced_counted_apart() {
+        let (a, b) = (Keys::generate().public_key(), Keys::generate().public_key());
+        let mut pubkeys = PubKeys::new();
+        pubkeys.add_author(&a);
+        pubkeys.add_author(&a);
+        pubkeys.add_referenced(&a);
+        pubkeys.add_referenced(&b);
+        assert_eq!(pubkeys.author_count(), 1);
+        assert_eq!(pubkeys.referenced_count(), 2);
+        assert_eq!(pubkeys.count(), 2);
+    }
+}
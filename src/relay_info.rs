@@ -0,0 +1,171 @@
+//! Fetching of NIP-11 relay information documents
+use crate::relays::canonical_url;
+use nostr_sdk::prelude::{RelayInformationDocument, Url};
+use reqwest::{Client, Proxy};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+/// Default deadline for fetching a relay information document
+pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A relay information document, with the fields not covered by
+/// [`RelayInformationDocument`]
+#[derive(Debug, Clone)]
+pub struct RelayInfo {
+    pub document: RelayInformationDocument,
+    /// `limitation.payment_required` is set, or `fees` lists any fee
+    pub payment_required: bool,
+    /// The document as served, empty if cached by an older version
+    pub raw: String,
+}
+
+/// NIP-11 documents fetched before, keyed by canonical relay URL, so
+/// periodic crawls don't fetch them again. Only successful fetches are
+/// cached. Can be saved to and loaded from a JSON file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct InfoCache {
+    entries: HashMap<String, CachedInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedInfo {
+    /// Unix timestamp of the fetch
+    fetched_at: u64,
+    document: RelayInformationDocument,
+    payment_required: bool,
+    #[serde(default)]
+    raw: String,
+}
+
+impl InfoCache {
+    /// The document of `url`, unless fetched more than `ttl` before `now`
+    /// (Unix timestamp)
+    pub fn get(&self, url: &Url, ttl: Duration, now: u64) -> Option<RelayInfo> {
+        self.entries
+            .get(&canonical_url(url))
+            .filter(|e| now.saturating_sub(e.fetched_at) <= ttl.as_secs())
+            .map(|e| RelayInfo {
+                document: e.document.clone(),
+                payment_required: e.payment_required,
+                raw: e.raw.clone(),
+            })
+    }
+
+    pub fn insert(&mut self, url: &Url, info: &RelayInfo, now: u64) {
+        self.entries.insert(
+            canonical_url(url),
+            CachedInfo {
+                fetched_at: now,
+                document: info.document.clone(),
+                payment_required: info.payment_required,
+                raw: info.raw.clone(),
+            },
+        );
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)
+    }
+}
+
+/// Why no relay information document could be obtained from a relay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The relay URL can't be mapped to HTTP(S)
+    InvalidScheme,
+    /// The relay did not answer within the deadline
+    Timeout,
+    /// The relay answered with a non-success HTTP status, e.g. 404
+    Status(u16),
+    /// The answer is not a valid relay information document
+    InvalidDocument,
+    /// The request failed, e.g. connection refused or TLS error
+    Unreachable(String),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::InvalidScheme => write!(f, "invalid scheme"),
+            SkipReason::Timeout => write!(f, "timeout"),
+            SkipReason::Status(code) => write!(f, "http status {code}"),
+            SkipReason::InvalidDocument => write!(f, "invalid document"),
+            SkipReason::Unreachable(e) => write!(f, "unreachable: {e}"),
+        }
+    }
+}
+
+/// Fetch the relay information document of `url`, giving up after `deadline`.
+/// A meaningful fraction of relays mishandle NIP-11, so every failure is
+/// reported as a [`SkipReason`] for the caller to record and move on.
+pub async fn fetch_relay_info(
+    url: &Url,
+    proxy: Option<SocketAddr>,
+    deadline: Duration,
+) -> Result<RelayInfo, SkipReason> {
+    match tokio::time::timeout(deadline, fetch(url, proxy)).await {
+        Ok(res) => res,
+        Err(_) => Err(SkipReason::Timeout),
+    }
+}
+
+async fn fetch(url: &Url, proxy: Option<SocketAddr>) -> Result<RelayInfo, SkipReason> {
+    let mut http_url = url.clone();
+    let scheme = match url.scheme() {
+        "wss" => "https",
+        "ws" => "http",
+        _ => return Err(SkipReason::InvalidScheme),
+    };
+    http_url
+        .set_scheme(scheme)
+        .map_err(|_| SkipReason::InvalidScheme)?;
+
+    let unreachable = |e: reqwest::Error| SkipReason::Unreachable(e.to_string());
+    let mut builder = Client::builder();
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(Proxy::all(format!("socks5h://{proxy}")).map_err(unreachable)?);
+    }
+    let client = builder.build().map_err(unreachable)?;
+    let response = client
+        .get(http_url)
+        .header("Accept", "application/nostr+json")
+        .send()
+        .await
+        .map_err(unreachable)?;
+    if !response.status().is_success() {
+        return Err(SkipReason::Status(response.status().as_u16()));
+    }
+    let body = response.text().await.map_err(unreachable)?;
+    let value: Value = serde_json::from_str(&body).map_err(|_| SkipReason::InvalidDocument)?;
+    let payment_required = payment_required(&value);
+    let document = serde_json::from_value(value).map_err(|_| SkipReason::InvalidDocument)?;
+    Ok(RelayInfo {
+        document,
+        payment_required,
+        raw: body,
+    })
+}
+
+fn payment_required(doc: &Value) -> bool {
+    let required = doc["limitation"]["payment_required"]
+        .as_bool()
+        .unwrap_or(false);
+    let fees = doc["fees"]
+        .as_object()
+        .map(|fees| {
+            fees.values()
+                .any(|f| f.as_array().map(|a| !a.is_empty()).unwrap_or(false))
+        })
+        .unwrap_or(false);
+    required || fees
+}
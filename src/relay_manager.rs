@@ -1,30 +1,250 @@
+use crate::clones::{self, DEFAULT_CLONE_THRESHOLD};
 use crate::processor::Processor;
-use crate::relays::Relays;
+use crate::relay_info::{self, InfoCache, RelayInfo, SkipReason, DEFAULT_FETCH_TIMEOUT};
+use crate::relays::{canonical_url, DedupStrictness, RelayMeta, Relays, RelaysDump};
+use crate::reputation::ReputationStore;
+use crate::stats::Stats;
 use crate::CliArgs;
-use crate::APP_SECRET_KEY;
-use nostr_sdk::prelude::FromSkStr;
 use nostr_sdk::{
     prelude::{
-        Client, Event, Filter, Keys, Kind, Options, RelayPoolNotification, Result, Tag, Timestamp,
-        Url,
+        get_profile, Client, ClientMessage, Event, EventBuilder, Filter, Keys, Kind, Metadata,
+        Options, RelayInformationDocument, RelayPoolNotification, Result, SubscriptionId, Tag,
+        TagKind, Timestamp, Url, XOnlyPublicKey,
     },
-    RelayMessage, RelayStatus,
+    Relay, RelayMessage, RelayOptions, RelayStatus,
 };
-use std::collections::HashSet;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::time::Duration;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
 
-use clap::Parser;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Serialize;
+use serde_json::Value;
 
 use git2::Repository;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use log::debug;
 use log::info;
 use log::trace;
+use log::warn;
 
 const MAX_ACTIVE_RELAYS: usize = 2; //usize::MAX;
 const PERIOD_START_PAST_SECS: u64 = 6 * 60 * 60;
+/// Kinds subscribed to, in addition to those given a lookback
+const CRAWL_KINDS: [Kind; 2] = [Kind::ContactList, Kind::RecommendRelay];
+/// Most kinds in one subscription filter, well below what relays reject
+const DEFAULT_MAX_FILTER_KINDS: usize = 10;
+/// How long a relay that said it's rate limiting us is not dialed, unless
+/// it says for how long
+const DEFAULT_RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(60);
+/// NIP-66 relay discovery event
+const KIND_RELAY_DISCOVERY: u64 = 30166;
+/// Number of NIP-05 identifiers resolved at once
+const NIP05_CONCURRENCY: usize = 16;
+/// Kinds subscribed to in relays only mode, all carrying relay URLs
+const RELAY_KINDS: [Kind; 3] = [Kind::ContactList, Kind::RecommendRelay, Kind::RelayList];
+/// Default time a relay serving events has to send EOSE before it is taken
+/// to never send one
+const DEFAULT_NO_EOSE_WINDOW: Duration = Duration::from_secs(60);
+/// Interval of the samples of the discovery curve
+const DISCOVERY_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// Default bound of the queue of discovered relays waiting to be dialed
+const DEFAULT_MAX_PENDING: usize = 4096;
+/// Id of the NIP-50 search subscription, next to the crawl subscription
+const SEARCH_SUBSCRIPTION_ID: &str = "search";
+const CONNECTIVITY_TIMEOUT: Duration = Duration::from_secs(10);
+/// No events for this long after some EOSE ends the crawl
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(20);
+const DEFAULT_MAX_CONCURRENT_CONNECTS: usize = 64;
+/// Number of relays probed at once by [`RelayManager::validate_relays`]
+const VALIDATION_BATCH_SIZE: usize = 256;
+/// Age after which relays are reported as stale, see
+/// [`RelayManager::with_stale_after`]
+pub const DEFAULT_STALE_AFTER: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Longest default period the adaptive lookback widens to, see
+/// [`RelayManager::with_adaptive_lookback`]
+pub const DEFAULT_MAX_LOOKBACK: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Which received events are duplicates, not passed on to the processor.
+/// Relay discovery and the stats see every event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DedupPolicy {
+    /// Pass on every event, also when received from several relays
+    None,
+    /// Pass on each event id once
+    #[default]
+    ById,
+    /// As `ById`, and for replaceable events (kinds 0, 3, 10000-19999 and,
+    /// per `d` tag, 30000-39999) pass on only versions newer than the newest
+    /// one seen from the same author
+    Replaceable,
+}
+
+/// Source of the current time for the silence and discovery idle stops, the
+/// event rate windows and the rate limit cooldowns, see
+/// [`RelayManager::with_clock`]. The maximum runtime runs on tokio's clock,
+/// which tests can pause and advance instead.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic system clock, the default [`Clock`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Chooses the proxy and options each relay is added to the pool with,
+/// e.g. a proxy for onion relays only, see [`RelayManager::with_relay_options`]
+pub type RelayOptionsFn =
+    dyn Fn(&Url, Option<&RelayMeta>) -> (Option<SocketAddr>, RelayOptions) + Send + Sync;
+
+/// Pauses and resumes the discovery of a running crawl, see
+/// [`RelayManager::pause_handle`]
+#[derive(Debug, Clone, Default)]
+pub struct PauseHandle(Arc<AtomicBool>);
+
+impl PauseHandle {
+    pub fn pause(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What happened to a relay connection, see [`TimelineEntry`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimelineEvent {
+    /// A connection attempt started
+    Connecting,
+    /// The connection attempt succeeded
+    Connected,
+    /// The relay sent EOSE for the subscription
+    Eose,
+    /// The health check evicted the relay
+    Evicted,
+    /// The relay was disconnected by the crawler
+    Disconnected,
+    /// The pool is refilled and reconnected, recorded without a relay
+    Reconnect,
+}
+
+/// One record of the connection timeline, see
+/// [`RelayManager::with_timeline_output`]
+#[derive(Debug, Clone, Serialize)]
+pub struct TimelineEntry {
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+    pub event: TimelineEvent,
+}
+
+/// Why the crawl loop ended, see [`RelayManager::stop_reason`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// Enough of the relays signalled EOSE, see
+    /// [`RelayManager::with_eose_completion_fraction`]
+    AllEose,
+    /// No events for a while after some relays signalled EOSE
+    Silence,
+    /// The maximum runtime was reached
+    Timeout,
+    /// No new relays were discovered for a while
+    DiscoveryIdle,
+    /// The relay cap was reached
+    RelayCap,
+    /// Interrupted, or the relay pool shut down
+    Shutdown,
+    /// No relay could be reached to continue the crawl
+    AllRelaysFailed,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::AllEose => write!(f, "relays signalled EOSE"),
+            StopReason::Silence => write!(f, "no more events"),
+            StopReason::Timeout => write!(f, "maximum runtime reached"),
+            StopReason::DiscoveryIdle => write!(f, "no new relays discovered"),
+            StopReason::RelayCap => write!(f, "relay cap reached"),
+            StopReason::Shutdown => write!(f, "shut down"),
+            StopReason::AllRelaysFailed => write!(f, "all relays failed"),
+        }
+    }
+}
+
+/// Result of validating a relay, see [`RelayManager::validate_relays`]
+#[derive(Debug, Serialize)]
+pub struct RelayValidation {
+    pub url: String,
+    pub reachable: bool,
+    /// NIPs listed in the NIP-11 document
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub supported_nips: Option<Vec<u16>>,
+    /// Why the NIP-11 document could not be fetched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nip11_skipped: Option<String>,
+}
+
+impl RelayValidation {
+    fn new(
+        url: Url,
+        reachable: bool,
+        info: Option<std::result::Result<RelayInformationDocument, SkipReason>>,
+    ) -> Self {
+        let (supported_nips, nip11_skipped) = match info {
+            Some(Ok(info)) => (info.supported_nips, None),
+            Some(Err(reason)) => (None, Some(reason.to_string())),
+            None => (None, None),
+        };
+        Self {
+            url: canonical_url(&url),
+            reachable,
+            supported_nips,
+            nip11_skipped,
+        }
+    }
+}
+
+/// Result of probing a single relay, see [`RelayManager::probe_relay`]
+#[derive(Debug, Serialize)]
+pub struct RelayProbe {
+    pub url: String,
+    pub reachable: bool,
+    /// Time taken to connect
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_latency_ms: Option<u64>,
+    /// The NIP-11 document
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nip11: Option<RelayInformationDocument>,
+    /// Why the NIP-11 document could not be fetched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nip11_skipped: Option<String>,
+}
 
 /// Keeps a set of active connections to relays
 pub struct RelayManager {
@@ -32,79 +252,1118 @@ pub struct RelayManager {
     relays: Relays,
     relay_client: Client,
     pub processor: Processor,
-    /// Time of last event seen (real time, Unix timestamp)
-    time_last_event: u64,
+    /// Crawl statistics
+    pub stats: Stats,
+    /// Time of last event seen (monotonic, unaffected by clock adjustments)
+    time_last_event: Instant,
+    clock: Arc<dyn Clock>,
+    /// RNG used for relay selection, seeded for reproducible crawls
+    rng: StdRng,
+    /// Health check polling interval, and how long a relay may stay
+    /// not connected before it is evicted from the pool
+    health_check: Option<(Duration, Duration)>,
+    /// Relays evicted by the health check, never dialed again
+    evicted: HashSet<Url>,
+    /// Why the crawl loop ended, once it did
+    stop_reason: Option<StopReason>,
+    /// Rate limited relays, not dialed until the given time
+    cooldowns: HashMap<Url, Instant>,
+    /// Also dial discovered relays, not only the ones known at the start
+    discovery_expands_pool: bool,
+    /// The only relays dialed, if discovery doesn't expand the pool
+    pinned: Option<HashSet<Url>>,
+    /// If not empty, only relays with a host matching one of these glob
+    /// patterns are dialed
+    host_patterns: Vec<String>,
+    /// After the crawl, dial every relay not dialed yet, in batches
+    full_sweep: bool,
+    /// Instead of crawling, only connect to the known relays and fetch
+    /// their NIP-11 documents
+    info_survey: bool,
+    /// Newly discovered relays waiting to be dialed, oldest first
+    pending: VecDeque<Url>,
+    /// Bound of `pending`, the oldest are dropped beyond it
+    max_pending: usize,
+    /// Relays dropped from `pending` when it was full
+    pending_dropped: u64,
+    /// Cooldown for rate limited relays that don't suggest a delay
+    rate_limit_cooldown: Duration,
+    /// Set while discovery is paused
+    paused: PauseHandle,
+    /// NIPs a relay must list in its NIP-11 document to be connected to
+    required_nips: Vec<u16>,
+    /// NIPs a relay must not list in its NIP-11 document to be connected to
+    forbidden_nips: Vec<u16>,
+    /// Relays not connected to because of their NIP-11 document
+    nip_rejected: HashSet<Url>,
+    /// Stop discovery once this many relays are known
+    max_relays: Option<usize>,
+    /// Fetch NIP-11 relay information for discovered relays after the crawl
+    fetch_info: bool,
+    /// Resolve the hosts of the discovered relays after the crawl
+    resolve_addrs: bool,
+    /// Deadline for each NIP-11 fetch
+    info_timeout: Duration,
+    /// NIP-11 documents fetched before
+    info_cache: InfoCache,
+    /// How long a cached NIP-11 document is used; not cached if unset
+    info_cache_ttl: Option<Duration>,
+    /// Interval for logging discovery progress
+    progress_interval: Option<Duration>,
+    /// Maximum number of events each relay should return per filter
+    per_relay_limit: Option<usize>,
+    /// Most kinds in one filter, larger lists are split over several filters
+    max_filter_kinds: usize,
+    /// Follow live events from now on, without EOSE or silence stop
+    tail: bool,
+    /// Lookback per kind, instead of the default period
+    kind_lookbacks: BTreeMap<Kind, Duration>,
+    /// The default period, widened by the adaptive lookback
+    lookback: Duration,
+    /// Widen the default period while fewer events than this were found,
+    /// up to the maximum lookback
+    adaptive_lookback: Option<(u64, Duration)>,
+    /// Caps the number of connection attempts in flight at any time
+    connect_permits: Arc<Semaphore>,
+    /// Crawl only the allowlisted relays
+    allowlist_mode: bool,
+    /// Identities to answer NIP-42 auth challenges with, one per relay
+    identity_pool: Vec<Keys>,
+    /// Fraction of connected/connecting relays to receive EOSE from before
+    /// stopping
+    eose_completion_fraction: f64,
+    /// Relays serving events without EOSE for this long are flagged no-EOSE
+    no_eose_window: Duration,
+    /// Leave the no-EOSE relays out of the EOSE completion
+    exclude_no_eose: bool,
+    /// Similarity of event sets above which relays are reported as clones
+    clone_threshold: f64,
+    /// Dump the relays for humans instead of as JSON
+    pretty_dump: bool,
+    /// Dump only the relays that sent events
+    productive_only: bool,
+    /// Stop the crawl after this long
+    max_runtime: Option<Duration>,
+    /// Relays whose newest event is older than this are reported as stale
+    stale_after: Duration,
+    /// File to write the discovery graph to, in DOT format
+    graph_path: Option<PathBuf>,
+    /// Best relays of a previous run, to connect to first
+    warm_start: Vec<Url>,
+    /// Relay reputation over past runs, updated at the end of the crawl
+    reputation: Option<ReputationStore>,
+    /// Relays a connection was attempted to
+    dialed: HashSet<Url>,
+    /// git-log arguments; the commits of the repository are walked if set
+    git_args: Option<CliArgs>,
+    /// SOCKS5 proxy for relay connections and NIP-11 fetches
+    proxy: Option<SocketAddr>,
+    /// Proxy and options per relay, overriding `proxy` for connections
+    relay_options: Option<Arc<RelayOptionsFn>>,
+    /// Notifications buffered on top of the pool's own channel
+    notification_buffer: Option<usize>,
+    /// Run the processor on its own task, fed by a channel of this size
+    processor_buffer_size: Option<usize>,
+    /// Start of the crawl
+    time_started: Instant,
+    /// Time a new relay was last discovered
+    time_last_discovery: Instant,
+    /// Stop once no new relay has been discovered for this long
+    discovery_idle: Option<Duration>,
+    /// When the subscription was sent to each relay, for the EOSE latency
+    subscribed_at: HashMap<Url, Instant>,
+    /// Which events are passed on to the processor
+    dedup_policy: DedupPolicy,
+    /// Newest `created_at` per replaceable event (author, kind, `d` tag)
+    newest_replaceable: HashMap<(XOnlyPublicKey, u64, String), u64>,
+    /// Keep only the relays and counters, not per-event state
+    streaming: bool,
+    /// Most events accepted from a relay per second
+    max_event_rate: Option<u64>,
+    /// Largest event accepted, in bytes of JSON
+    max_event_size: Option<usize>,
+    /// Start of the current one second window, and events in it, per relay
+    event_rates: HashMap<Url, (Instant, u64)>,
+    /// Only subscribe to relay carrying kinds, and only extract relays
+    relays_only: bool,
+    /// Subscribe to metadata, and resolve the NIP-05 identifiers in it
+    metadata_discovery: bool,
+    /// NIP-50 search query sent to the relays supporting it
+    search: Option<String>,
+    /// Relay the NIP-66 relay discovery events are published to at the end
+    /// of the crawl
+    monitor_relay: Option<Url>,
+    /// NIP-05 identifiers resolved, or being resolved
+    nip05_resolved: HashSet<String>,
+    nip05_permits: Arc<Semaphore>,
+    /// Relays found by NIP-05 resolution, with the relay that served the
+    /// metadata
+    nip05_sender: mpsc::UnboundedSender<(Url, Vec<String>)>,
+    nip05_receiver: mpsc::UnboundedReceiver<(Url, Vec<String>)>,
+    /// The relays have been dumped, no need to do it when dropped
+    dumped: bool,
+    /// Connection latencies, with the time connected (Unix milliseconds)
+    latency_sender: mpsc::UnboundedSender<(Url, Duration, u64)>,
+    latency_receiver: mpsc::UnboundedReceiver<(Url, Duration, u64)>,
+    /// Subscription failures of the connection tasks, with the error
+    subscription_failure_sender: mpsc::UnboundedSender<(Url, String)>,
+    subscription_failure_receiver: mpsc::UnboundedReceiver<(Url, String)>,
+    /// Connection lifecycle records
+    timeline: Vec<TimelineEntry>,
+    /// File to write the timeline to, as JSON
+    timeline_path: Option<PathBuf>,
+    /// Where the change log of the relays is written
+    change_log_path: Option<PathBuf>,
+    /// Where the discovery curve is written
+    discovery_curve_path: Option<PathBuf>,
+    /// Where the pubkeys seen are written, as npubs
+    npubs_path: Option<PathBuf>,
+    /// Directory where each dump is also saved, and number of dumps kept
+    dump_rotation: Option<(PathBuf, usize)>,
 }
 
 impl RelayManager {
     pub fn new(app_keys: Keys, processor: Processor) -> Self {
-        let opts = Options::new(); //.wait_for_send(false);
+        let opts = Options::new();
         let relay_client = Client::new_with_opts(&app_keys, opts);
-        let _proxy = Some(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9050)));
+        let (latency_sender, latency_receiver) = mpsc::unbounded_channel();
+        let (subscription_failure_sender, subscription_failure_receiver) =
+            mpsc::unbounded_channel();
+        let (nip05_sender, nip05_receiver) = mpsc::unbounded_channel();
         Self {
             // app_keys,
             relays: Relays::new(),
             relay_client,
             processor,
-            time_last_event: Self::now(),
+            stats: Stats::new(),
+            time_last_event: Instant::now(),
+            clock: Arc::new(SystemClock),
+            rng: StdRng::from_entropy(),
+            health_check: None,
+            evicted: HashSet::new(),
+            stop_reason: None,
+            cooldowns: HashMap::new(),
+            discovery_expands_pool: true,
+            full_sweep: false,
+            info_survey: false,
+            host_patterns: Vec::new(),
+            pending: VecDeque::new(),
+            max_pending: DEFAULT_MAX_PENDING,
+            pending_dropped: 0,
+            pinned: None,
+            rate_limit_cooldown: DEFAULT_RATE_LIMIT_COOLDOWN,
+            paused: PauseHandle::default(),
+            required_nips: Vec::new(),
+            forbidden_nips: Vec::new(),
+            nip_rejected: HashSet::new(),
+            max_relays: None,
+            fetch_info: false,
+            resolve_addrs: false,
+            info_timeout: DEFAULT_FETCH_TIMEOUT,
+            info_cache: InfoCache::default(),
+            info_cache_ttl: None,
+            progress_interval: None,
+            per_relay_limit: None,
+            max_filter_kinds: DEFAULT_MAX_FILTER_KINDS,
+            tail: false,
+            kind_lookbacks: BTreeMap::new(),
+            lookback: Duration::from_secs(PERIOD_START_PAST_SECS),
+            adaptive_lookback: None,
+            connect_permits: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_CONNECTS)),
+            allowlist_mode: false,
+            identity_pool: Vec::new(),
+            eose_completion_fraction: 1.0,
+            no_eose_window: DEFAULT_NO_EOSE_WINDOW,
+            exclude_no_eose: false,
+            clone_threshold: DEFAULT_CLONE_THRESHOLD,
+            pretty_dump: false,
+            productive_only: false,
+            max_runtime: None,
+            stale_after: DEFAULT_STALE_AFTER,
+            graph_path: None,
+            warm_start: Vec::new(),
+            reputation: None,
+            dialed: HashSet::new(),
+            git_args: None,
+            proxy: None,
+            relay_options: None,
+            notification_buffer: None,
+            processor_buffer_size: None,
+            time_started: Instant::now(),
+            time_last_discovery: Instant::now(),
+            discovery_idle: None,
+            subscribed_at: HashMap::new(),
+            dedup_policy: DedupPolicy::default(),
+            newest_replaceable: HashMap::new(),
+            streaming: false,
+            max_event_rate: None,
+            max_event_size: None,
+            event_rates: HashMap::new(),
+            relays_only: false,
+            metadata_discovery: false,
+            search: None,
+            monitor_relay: None,
+            nip05_resolved: HashSet::new(),
+            nip05_permits: Arc::new(Semaphore::new(NIP05_CONCURRENCY)),
+            nip05_sender,
+            nip05_receiver,
+            dumped: false,
+            latency_sender,
+            latency_receiver,
+            subscription_failure_sender,
+            subscription_failure_receiver,
+            timeline: Vec::new(),
+            timeline_path: None,
+            change_log_path: None,
+            discovery_curve_path: None,
+            npubs_path: None,
+            dump_rotation: None,
         }
     }
 
-    fn add_bootstrap_relays_if_needed(&mut self, bootstrap_relays: Vec<&str>) {
-        for us in &bootstrap_relays {
-            if self.relays.count() >= MAX_ACTIVE_RELAYS {
-                return;
+    /// Use a fixed seed for relay selection, so that the same seed and the
+    /// same discovered set always result in the same relays being picked.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// Poll the status of the relays in the pool every `interval`, and evict
+    /// relays that lost their connection, or have been connecting for
+    /// `stuck_after`, replacing them with other discovered relays.
+    pub fn with_health_check(mut self, interval: Duration, stuck_after: Duration) -> Self {
+        self.health_check = Some((interval, stuck_after));
+        self
+    }
+
+    /// Stop discovering relays once `max_relays` are known, and end the crawl
+    pub fn with_max_relays(mut self, max_relays: usize) -> Self {
+        self.max_relays = Some(max_relays);
+        self
+    }
+
+    /// Fetch the NIP-11 relay information of all discovered relays at the end
+    /// of the crawl, and include it in the dump
+    pub fn with_relay_info(mut self, fetch_info: bool) -> Self {
+        self.fetch_info = fetch_info;
+        self
+    }
+
+    /// Also include each NIP-11 document verbatim in the dump, as served, for
+    /// the fields not parsed; off by default to keep the dump compact
+    pub fn with_raw_relay_info(mut self, include_raw: bool) -> Self {
+        self.relays.set_keep_raw_info(include_raw);
+        self
+    }
+
+    /// Wait for each unsubscription and NIP-42 auth message to be written to
+    /// the relay's connection before going on (off by default), blocking on
+    /// each relay in turn, up to a minute for a relay that isn't connected.
+    /// The subscription isn't affected, relays send it themselves once
+    /// connected. Replaces the relay client, so set it before [`Self::run`].
+    pub fn with_wait_for_send(mut self, wait: bool) -> Self {
+        let keys = self.relay_client.keys();
+        self.relay_client = Client::new_with_opts(&keys, Options::new().wait_for_send(wait));
+        self
+    }
+
+    /// Resolve the host of each discovered relay at the end of the crawl,
+    /// include the address in the dump and report the relays sharing an
+    /// address, e.g. many relays hosted on one server. Onion relays are not
+    /// resolved, and nothing is when connecting through a proxy, not to
+    /// leak the lookups.
+    pub fn with_addr_resolution(mut self, resolve_addrs: bool) -> Self {
+        self.resolve_addrs = resolve_addrs;
+        self
+    }
+
+    /// Give up on a NIP-11 fetch after `timeout` (5 seconds by default)
+    pub fn with_relay_info_timeout(mut self, timeout: Duration) -> Self {
+        self.info_timeout = timeout;
+        self
+    }
+
+    /// Cache the fetched NIP-11 documents, and use them instead of fetching
+    /// again until they are `ttl` old. The cache lasts for the manager's
+    /// lifetime, across runs; see [`Self::with_nip11_cache`] to keep it
+    /// across processes.
+    pub fn with_nip11_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.info_cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Start with the NIP-11 documents in `cache`, e.g. loaded from the file
+    /// saved by a previous process from [`Self::nip11_cache`]
+    pub fn with_nip11_cache(mut self, cache: InfoCache) -> Self {
+        self.info_cache = cache;
+        self
+    }
+
+    pub fn nip11_cache(&self) -> &InfoCache {
+        &self.info_cache
+    }
+
+    /// Log the number of discovered relays every `interval` during the crawl
+    pub fn with_progress(mut self, interval: Duration) -> Self {
+        self.progress_interval = Some(interval);
+        self
+    }
+
+    /// Ask relays for at most `limit` events per filter (NIP-01 `limit`), for
+    /// faster crawls with representative samples
+    pub fn with_per_relay_limit(mut self, limit: Option<usize>) -> Self {
+        self.per_relay_limit = limit;
+        self
+    }
+
+    /// Put at most `max` kinds (10 by default) in a subscription filter,
+    /// splitting longer kind lists over several filters of the subscription,
+    /// as some relays reject oversized filters. Panics if `max` is 0.
+    pub fn with_max_filter_kinds(mut self, max: usize) -> Self {
+        assert!(max > 0, "filters need room for at least one kind");
+        self.max_filter_kinds = max;
+        self
+    }
+
+    /// Instead of crawling the past period, subscribe to events from now on
+    /// and keep discovering relays until shut down (e.g. with Ctrl-C)
+    pub fn with_tail(mut self, tail: bool) -> Self {
+        self.tail = tail;
+        self
+    }
+
+    /// Crawl events of `kind` over the past `lookback` instead of the default
+    /// period, e.g. a longer one for rare kinds carrying relay hints. Kinds not
+    /// crawled by default are added to the subscription.
+    pub fn with_kind_lookback(mut self, kind: Kind, lookback: Duration) -> Self {
+        self.kind_lookbacks.insert(kind, lookback);
+        self
+    }
+
+    /// When the crawl stops with fewer than `min_events` events, double the
+    /// default period and subscribe again over the widened period, again
+    /// until it stops, until there are enough events or the period reaches
+    /// `max_lookback` (see [`DEFAULT_MAX_LOOKBACK`]). Events already received
+    /// come again, they count once when event ids are tracked. Kinds given
+    /// their own lookback are not widened. Not in tail mode.
+    pub fn with_adaptive_lookback(mut self, min_events: u64, max_lookback: Duration) -> Self {
+        self.adaptive_lookback = Some((min_events, max_lookback));
+        self
+    }
+
+    /// Allow at most `permits` connection attempts in flight at once (64 by
+    /// default), across all connects, reconnects, backfills and probes. This
+    /// covers the first attempt to each relay; later automatic reconnects
+    /// are made by the relay pool itself.
+    pub fn with_max_concurrent_connects(mut self, permits: usize) -> Self {
+        self.connect_permits = Arc::new(Semaphore::new(permits));
+        self
+    }
+
+    /// Crawl only the relays in `allowlist`, e.g. to audit a known set of
+    /// relays. Relays discovered outside of it are counted for the report,
+    /// but never connected to. Bootstrap relays not in the list are ignored.
+    pub fn with_allowlist(mut self, allowlist: HashSet<Url>) -> Self {
+        self.relays.set_allowlist(allowlist);
+        let relays = &self.relays;
+        self.warm_start.retain(|url| relays.is_allowed(url));
+        self.allowlist_mode = true;
+        self
+    }
+
+    /// Answer NIP-42 auth challenges with identities from `pool` instead of
+    /// the app keys, so that relays rate limiting per pubkey don't throttle
+    /// the whole crawl. Each relay always gets the same identity.
+    pub fn with_identity_pool(mut self, pool: Vec<Keys>) -> Self {
+        self.identity_pool = pool;
+        self
+    }
+
+    /// Stop once `fraction` (1.0 by default) of the connected and connecting
+    /// relays have sent EOSE, instead of waiting for a few slow relays.
+    /// Panics unless `0.0 < fraction <= 1.0`.
+    pub fn with_eose_completion_fraction(mut self, fraction: f64) -> Self {
+        assert!(
+            fraction > 0.0 && fraction <= 1.0,
+            "EOSE completion fraction must be in (0.0, 1.0], got {fraction}"
+        );
+        self.eose_completion_fraction = fraction;
+        self
+    }
+
+    /// Flag the relays that served events, but no EOSE within `window`
+    /// (a minute by default) of subscribing, as no-EOSE in the report
+    pub fn with_no_eose_window(mut self, window: Duration) -> Self {
+        self.no_eose_window = window;
+        self
+    }
+
+    /// Leave the no-EOSE relays (see [`Self::with_no_eose_window`]) out of
+    /// the EOSE completion, so that relays streaming forever don't stall
+    /// the crawl
+    pub fn with_no_eose_excluded(mut self, exclude: bool) -> Self {
+        self.exclude_no_eose = exclude;
+        self
+    }
+
+    /// Report relays whose received event sets have an estimated Jaccard
+    /// similarity of at least `threshold` (0.9 by default) as clones.
+    /// Panics unless `0.0 < threshold <= 1.0`.
+    pub fn with_clone_threshold(mut self, threshold: f64) -> Self {
+        assert!(
+            threshold > 0.0 && threshold <= 1.0,
+            "clone threshold must be in (0.0, 1.0], got {threshold}"
+        );
+        self.clone_threshold = threshold;
+        self
+    }
+
+    /// Print the relays at the end of the crawl as a sorted, annotated list
+    /// instead of JSON
+    pub fn with_pretty_dump(mut self, pretty: bool) -> Self {
+        self.pretty_dump = pretty;
+        self
+    }
+
+    /// Dump only the relays that sent at least one event, for a curated relay
+    /// list instead of every relay referenced
+    pub fn with_productive_only(mut self, productive_only: bool) -> Self {
+        self.productive_only = productive_only;
+        self
+    }
+
+    /// End the crawl after `max_runtime`, dumping the relays found so far
+    pub fn with_max_runtime(mut self, max_runtime: Duration) -> Self {
+        self.max_runtime = Some(max_runtime);
+        self
+    }
+
+    /// Report relays whose newest event is older than `stale_after` (30 days
+    /// by default) as stale
+    pub fn with_stale_after(mut self, stale_after: Duration) -> Self {
+        self.stale_after = stale_after;
+        self
+    }
+
+    /// Write the relay discovery graph to `path` at the end of the crawl, in
+    /// Graphviz DOT format, with an edge from each relay to the relays
+    /// referenced in the events it served
+    pub fn with_graph_output(mut self, path: PathBuf) -> Self {
+        self.graph_path = Some(path);
+        self
+    }
+
+    /// Start from the best relays of a previous run, as printed in its
+    /// `dump`: the initial active relays are its top scored relays (see
+    /// [`RelaysDump::top_relays`]) instead of a random pick
+    pub fn with_warm_start(mut self, dump: &RelaysDump) -> Self {
+        self.warm_start = Vec::new();
+        for entry in dump.top_relays(MAX_ACTIVE_RELAYS) {
+            if let Ok(url) = Url::parse(&entry.url) {
+                self.relays.add_with_reason(&entry.url, "warm start");
+                self.warm_start.push(url);
             }
-            self.relays.add(us);
         }
+        self
+    }
+
+    /// Keep relay reputations in `store`: the initial active relays are the
+    /// ones with the best reputation (unless set by [`Self::with_warm_start`]),
+    /// and the reputation of each relay dialed is updated at the end of the
+    /// crawl, see [`ReputationStore::record_run`]
+    pub fn with_reputation(mut self, store: ReputationStore) -> Self {
+        if self.warm_start.is_empty() {
+            for url in store.top(MAX_ACTIVE_RELAYS) {
+                self.relays.add_with_reason(url.as_str(), "reputation");
+                self.warm_start.push(url);
+            }
+        }
+        self.reputation = Some(store);
+        self
+    }
+
+    /// Why the crawl ended, `None` before it did or when it failed to start
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    /// The relay reputations, updated by the crawl, e.g. to save them
+    pub fn reputation(&self) -> Option<&ReputationStore> {
+        self.reputation.as_ref()
+    }
+
+    /// Write the timeline of connection attempts, connections, EOSEs,
+    /// evictions, disconnections and reconnect cycles to `path` at the end
+    /// of the crawl, as a JSON array of [`TimelineEntry`] sorted by time
+    pub fn with_timeline_output(mut self, path: PathBuf) -> Self {
+        self.timeline_path = Some(path);
+        self
+    }
+
+    /// Sample the number of relays discovered every few seconds during the
+    /// crawl, and write the samples to `path` at the end, as CSV with an
+    /// `elapsed_secs,relays` header, e.g. to find where discovery levels off
+    pub fn with_discovery_curve_output(mut self, path: PathBuf) -> Self {
+        self.discovery_curve_path = Some(path);
+        self
+    }
+
+    /// Write the pubkeys seen, authors and referenced, to `path` at the end
+    /// of the crawl, one bech32 `npub` per line, sorted
+    pub fn with_npubs_output(mut self, path: PathBuf) -> Self {
+        self.npubs_path = Some(path);
+        self
+    }
+
+    /// Also save the dump in `dir` at the end of each crawl, as
+    /// `relays-<timestamp>.json`, keeping the `keep` newest dumps there and
+    /// deleting older ones, e.g. for a rolling history of periodic crawls
+    pub fn with_dump_rotation(mut self, dir: PathBuf, keep: usize) -> Self {
+        self.dump_rotation = Some((dir, keep));
+        self
+    }
+
+    /// Log every relay added to or removed from the known relays, with the
+    /// reason, and write the log to `path` at the end of the crawl, as a JSON
+    /// array of [`crate::relays::RelayChange`]. Set it first, relays added
+    /// before are not logged.
+    pub fn with_change_log_output(mut self, path: PathBuf) -> Self {
+        self.relays.enable_change_log();
+        self.change_log_path = Some(path);
+        self
+    }
+
+    fn add_timeline(&mut self, relay: Option<&Url>, event: TimelineEvent) {
+        self.timeline.push(TimelineEntry {
+            timestamp: unix_millis(),
+            relay: relay.map(canonical_url),
+            event,
+        });
+    }
+
+    /// Walk the commits of the git repository given in `args` (the current
+    /// directory by default) when picking relays
+    pub fn with_git_args(mut self, args: CliArgs) -> Self {
+        self.git_args = Some(args);
+        self
+    }
+
+    /// Connect to relays, and fetch NIP-11 documents, through the SOCKS5
+    /// proxy at `proxy` (e.g. Tor at 127.0.0.1:9050). Relay hostnames are
+    /// then resolved by the proxy, not locally.
+    pub fn with_proxy(mut self, proxy: SocketAddr) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Add each relay to the pool with the proxy and options `f` returns for
+    /// it and what is known about it, instead of the proxy set by
+    /// [`Self::with_proxy`] and the default options. NIP-11 documents are
+    /// still fetched through that proxy.
+    pub fn with_relay_options(mut self, f: Arc<RelayOptionsFn>) -> Self {
+        self.relay_options = Some(f);
+        self
+    }
+
+    /// The proxy and options to add `url` to a pool with
+    fn relay_options(&self, url: &Url) -> (Option<SocketAddr>, RelayOptions) {
+        match &self.relay_options {
+            Some(f) => f(url, self.relays.get(url)),
+            None => (self.proxy, RelayOptions::default()),
+        }
+    }
+
+    /// Buffer up to `size` pool notifications on top of the 1024 of the
+    /// nostr-sdk channel, read from it by a separate task, so that bursts
+    /// of events don't overflow it. Each buffered notification holds an
+    /// event, usually under a kilobyte but up to the maximum event size.
+    /// Notifications missed on overflow are counted and reported.
+    pub fn with_notification_buffer(mut self, size: usize) -> Self {
+        self.notification_buffer = Some(size.max(1));
+        self
+    }
+
+    /// Run the processor on a separate task, fed through a channel holding up
+    /// to `size` events, so that a slow processor doesn't hold up relay
+    /// discovery. When the channel is full, event intake waits for it.
+    pub fn with_processor_buffer_size(mut self, size: usize) -> Self {
+        self.processor_buffer_size = Some(size);
+        self
+    }
+
+    /// Set which relay URLs count as the same relay (see [`DedupStrictness`],
+    /// `Loose` by default). Set it before adding relays, e.g. with
+    /// [`RelayManager::with_allowlist`].
+    pub fn with_dedup_strictness(mut self, dedup: DedupStrictness) -> Self {
+        self.relays.set_dedup_strictness(dedup);
+        self
+    }
+
+    /// With `false`, only dial the relays known when the crawl starts (seeds
+    /// and bootstrap relays); discovered relays are still recorded and
+    /// reported, but never connected to. `true` by default.
+    pub fn with_discovery_expands_pool(mut self, expands: bool) -> Self {
+        self.discovery_expands_pool = expands;
+        self
+    }
+
+    /// Queue at most `max` newly discovered relays to be dialed (4096 by
+    /// default); when the queue is full the oldest one is dropped from it,
+    /// it can still be picked at random
+    pub fn with_max_pending(mut self, max: usize) -> Self {
+        self.max_pending = max;
+        self
+    }
+
+    /// The newly discovered relays waiting to be dialed, oldest first. They
+    /// are dialed first when the pool is refilled.
+    pub fn pending_relays(&self) -> &VecDeque<Url> {
+        &self.pending
+    }
+
+    /// After the crawl, crawl the relays not dialed yet in batches of up to
+    /// `MAX_ACTIVE_RELAYS`, each batch until it stops as the crawl does,
+    /// until every relay found was dialed, to pick up the relays only known
+    /// to few. Not in tail mode, and the maximum runtime still applies to
+    /// the whole crawl.
+    pub fn with_full_sweep(mut self, full_sweep: bool) -> Self {
+        self.full_sweep = full_sweep;
+        self
+    }
+
+    /// Accept placeholder relay URLs such as `ws://localhost` or
+    /// `wss://relay`, rejected by default (see [`crate::relays::is_placeholder`]),
+    /// e.g. to crawl a local test relay. Set it before adding relays.
+    pub fn with_placeholders_allowed(mut self, allow: bool) -> Self {
+        self.relays.set_allow_placeholders(allow);
+        self
+    }
+
+    /// Instead of crawling, survey the known relays (seeds, bootstrap relays
+    /// and relay lists): connect to each, a batch at a time, without
+    /// subscribing, fetch its NIP-11 document meanwhile, and disconnect.
+    /// Much faster and lighter than a crawl when only the relay metadata
+    /// is wanted, but no relays are discovered.
+    pub fn with_info_survey(mut self, info_survey: bool) -> Self {
+        self.info_survey = info_survey;
+        self
+    }
+
+    /// Take the time from `clock` instead of the system clock, e.g. to test
+    /// the stop conditions without waiting
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.time_last_event = clock.now();
+        self.time_last_discovery = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Back off a relay that says it's rate limiting us (a `rate-limited`
+    /// NOTICE, or OK message) for `cooldown` (a minute by default), unless
+    /// the message suggests a delay: the relay is dropped from the pool and
+    /// not dialed again until then
+    pub fn with_rate_limit_cooldown(mut self, cooldown: Duration) -> Self {
+        self.rate_limit_cooldown = cooldown;
+        self
+    }
+
+    /// Pause discovery: events keep coming in from the connected relays,
+    /// but no new relays are added and none are dialed, and the discovery
+    /// idle time doesn't run, until [`Self::resume`]
+    pub fn pause(&self) {
+        self.paused.pause();
+    }
+
+    pub fn resume(&self) {
+        self.paused.resume();
+    }
+
+    /// Handle to [`Self::pause`] and [`Self::resume`] the crawl from another
+    /// task while it runs
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.paused.clone()
+    }
+
+    /// Stop the crawl once no new relay has been discovered for `idle`, even
+    /// if events keep coming in
+    pub fn cancel_after_discovery_idle(mut self, idle: Duration) -> Self {
+        self.discovery_idle = Some(idle);
+        self
+    }
+
+    /// Choose which events count as duplicates and are not passed on to
+    /// the processor ([`DedupPolicy::ById`] by default)
+    pub fn with_dedup_policy(mut self, policy: DedupPolicy) -> Self {
+        self.dedup_policy = policy;
+        if policy != DedupPolicy::None {
+            self.stats.track_event_ids = true;
+        }
+        self
+    }
+
+    /// Only connect to relays listing all of `nips` in their NIP-11
+    /// document. The document is fetched before connecting; relays without
+    /// one are not connected to.
+    pub fn require_nips(mut self, nips: Vec<u16>) -> Self {
+        self.required_nips = nips;
+        self
+    }
+
+    /// Don't connect to relays listing any of `nips` in their NIP-11
+    /// document. The document is fetched before connecting; relays without
+    /// one are connected to.
+    pub fn forbid_nips(mut self, nips: Vec<u16>) -> Self {
+        self.forbidden_nips = nips;
+        self
+    }
+
+    /// Only connect to relays whose host matches one of `patterns`, globs
+    /// where `*` matches any characters, e.g. `*.de` or `relay.example.com`,
+    /// case insensitive. Other relays are still discovered and recorded.
+    pub fn with_host_patterns(mut self, patterns: Vec<String>) -> Self {
+        self.host_patterns = patterns;
+        self
+    }
+
+    /// Fast mode for when only the relays matter: subscribe only to the
+    /// kinds carrying relay URLs (contact lists, relay recommendations and
+    /// relay lists, ignoring [`Self::with_kind_lookback`] for other kinds),
+    /// and don't pass events to the processor or collect event statistics
+    /// beyond the counts, only extract the relays and drop the event
+    pub fn relays_only(mut self, relays_only: bool) -> Self {
+        self.relays_only = relays_only;
+        self
+    }
+
+    /// Also discover relays from user metadata (kind 0): subscribe to it,
+    /// and resolve the NIP-05 identifiers in it to the relays listed in the
+    /// `.well-known/nostr.json` file of their domain. Relays listed in the
+    /// metadata itself are picked up from any metadata received.
+    pub fn with_metadata_discovery(mut self, metadata_discovery: bool) -> Self {
+        self.metadata_discovery = metadata_discovery;
+        self
+    }
+
+    /// Also send the relays listing NIP-50 in their NIP-11 document the
+    /// crawl filters with the `search` query, in a separate subscription.
+    /// Other relays only get the crawl filters. The events matching the
+    /// query are counted per relay and reported.
+    pub fn with_search(mut self, search: String) -> Self {
+        self.search = Some(search);
+        self
+    }
+
+    /// At the end of the crawl, publish the [`Self::build_monitor_events`]
+    /// to `relay`, over the crawl connection if the relay was crawled
+    pub fn with_monitor_relay(mut self, relay: Url) -> Self {
+        self.monitor_relay = Some(relay);
+        self
+    }
+
+    /// Drop events larger than `size` bytes of JSON, so that a relay can't
+    /// exhaust memory with huge events. Dropped events are not counted in
+    /// any stats, the drops per relay are reported.
+    pub fn with_max_event_size(mut self, size: usize) -> Self {
+        self.max_event_size = Some(size);
+        self
+    }
+
+    /// Accept at most `rate` events per second from each relay, and drop
+    /// the excess, so a flooding relay can't dominate the crawl. Dropped
+    /// events are not counted in any stats, the drops per relay are reported.
+    pub fn with_max_event_rate(mut self, rate: u64) -> Self {
+        self.max_event_rate = Some(rate);
+        self
+    }
+
+    /// Bounded memory mode for large crawls: events are passed on to the
+    /// processor and forgotten, only the relays and counters are kept. Event
+    /// ids (so the unique event count) and the discovery graph edges are not
+    /// kept, and deduplication is off. A dedup policy set after this turns
+    /// deduplication, and the event id set it needs, back on.
+    pub fn with_streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self.stats.track_event_ids = !streaming;
+        if streaming {
+            self.dedup_policy = DedupPolicy::None;
+        }
+        self
+    }
+
+    /// Whether `event`, seen for the first time if `new`, is a duplicate
+    /// under the dedup policy
+    fn is_duplicate(&mut self, event: &Event, new: bool) -> bool {
+        match self.dedup_policy {
+            DedupPolicy::None => false,
+            DedupPolicy::ById => !new,
+            DedupPolicy::Replaceable => {
+                if !new {
+                    return true;
+                }
+                let kind = event.kind.as_u64();
+                let identifier = match kind {
+                    0 | 3 | 10000..=19999 => String::new(),
+                    30000..=39999 => event
+                        .tags
+                        .iter()
+                        .find_map(|t| match t {
+                            Tag::Identifier(d) => Some(d.clone()),
+                            _ => None,
+                        })
+                        .unwrap_or_default(),
+                    _ => return false,
+                };
+                let created_at = event.created_at.as_u64();
+                let newest = self
+                    .newest_replaceable
+                    .entry((event.pubkey, kind, identifier))
+                    .or_insert(0);
+                if created_at <= *newest {
+                    return true;
+                }
+                *newest = created_at;
+                false
+            }
+        }
+    }
+
+    /// The identity to authenticate to `url` with, picked from the identity
+    /// pool by a hash of the URL
+    fn auth_identity(&self, url: &Url) -> Keys {
+        if self.identity_pool.is_empty() {
+            return self.relay_client.keys();
+        }
+        let mut hasher = DefaultHasher::new();
+        url.as_str().hash(&mut hasher);
+        let idx = hasher.finish() % self.identity_pool.len() as u64;
+        self.identity_pool[idx as usize].clone()
+    }
+
+    /// Answer a NIP-42 auth challenge from a relay
+    async fn authenticate(&self, url: &Url, challenge: String) -> Result<()> {
+        let keys = self.auth_identity(url);
+        let event = EventBuilder::auth(challenge, url.clone()).to_event(&keys)?;
+        self.relay_client
+            .send_msg_to(url.to_string(), ClientMessage::new_auth(event))
+            .await?;
+        debug!("Authenticated to {url} as {}", keys.public_key());
+        Ok(())
+    }
+
+    /// Check the relay's NIP-11 document against the required and forbidden
+    /// NIPs, fetching it first if not fetched yet. Also fetched for a search,
+    /// to know whether the relay supports NIP-50.
+    async fn meets_nip_requirements(&mut self, url: &Url) -> bool {
+        if self.required_nips.is_empty() && self.forbidden_nips.is_empty() && self.search.is_none()
+        {
+            return true;
+        }
+        if self.nip_rejected.contains(url) {
+            return false;
+        }
+        if self
+            .relays
+            .get(url)
+            .map(|m| m.info.is_none())
+            .unwrap_or(true)
+        {
+            self.fetch_relay_info(url).await;
+        }
+        let meets = match self.relays.get(url) {
+            Some(meta) => {
+                self.required_nips.iter().all(|n| meta.supports_nip(*n))
+                    && !self.forbidden_nips.iter().any(|n| meta.supports_nip(*n))
+            }
+            None => self.required_nips.is_empty(),
+        };
+        if !meets {
+            debug!("Skipping relay {url}, NIP requirements not met");
+            self.nip_rejected.insert(url.clone());
+        }
+        meets
+    }
+
+    fn is_oversized(&self, event: &Event) -> bool {
+        self.max_event_size
+            .map(|max| event.as_json().len() > max)
+            .unwrap_or(false)
+    }
+
+    /// Count an event from `url` in its current one second window, true if
+    /// the relay is over the event rate cap
+    fn over_event_rate(&mut self, url: &Url) -> bool {
+        let max = match self.max_event_rate {
+            Some(max) => max,
+            None => return false,
+        };
+        let now = self.clock.now();
+        let (window_start, count) = self.event_rates.entry(url.clone()).or_insert((now, 0));
+        if now.duration_since(*window_start) >= Duration::from_secs(1) {
+            *window_start = now;
+            *count = 0;
+        }
+        *count += 1;
+        if *count == max + 1 {
+            info!("Relay {url} is flooding, over {max} events per second, dropping the excess");
+        }
+        *count > max
+    }
+
+    fn cooling_down(&self, url: &Url) -> bool {
+        self.cooldowns
+            .get(url)
+            .map(|until| self.clock.now() < *until)
+            .unwrap_or(false)
+    }
+
+    /// The relay may be dialed: it's not cooling down, pinned if the pool
+    /// is, and its host matches the host patterns if any
+    fn dialable(&self, url: &Url) -> bool {
+        let pinned = match &self.pinned {
+            Some(pinned) => pinned.contains(url),
+            None => true,
+        };
+        let host_matches = self.host_patterns.is_empty()
+            || url.host_str().is_some_and(|host| {
+                self.host_patterns
+                    .iter()
+                    .any(|pattern| glob_matches(pattern, host))
+            });
+        pinned && host_matches && self.relays.is_allowed(url) && !self.cooling_down(url)
+    }
+
+    /// Drop a relay that said it's rate limiting us from the pool, and don't
+    /// dial it until its cooldown is over
+    async fn back_off(&mut self, url: &Url, message: &str) {
+        let cooldown = suggested_delay(message).unwrap_or(self.rate_limit_cooldown);
+        info!(
+            "Relay {} is rate limiting, backing off for {}",
+            canonical_url(url),
+            format_elapsed(cooldown)
+        );
+        self.cooldowns
+            .insert(url.clone(), self.clock.now() + cooldown);
+        if let Err(e) = self.relay_client.remove_relay(url.to_string()).await {
+            debug!("Cannot remove relay {url}: {e}");
+        }
+    }
+
+    fn relay_cap_reached(&self) -> bool {
+        self.max_relays
+            .map(|max| self.relays.count() >= max)
+            .unwrap_or(false)
+    }
+
+    /// Add a relay found in an event served by `source`, unless the relay
+    /// cap is reached
+    fn add_discovered_relay(&mut self, source: &Url, s: &str) -> bool {
+        let url = match Url::parse(s) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        if !self.relays.is_allowed(&url) {
+            // Only counted, for the allowlist report
+            self.relays.add(s);
+            return false;
+        }
+        if self.relay_cap_reached() {
+            self.stats.count_capped_relay_hints += 1;
+            return false;
+        }
+        if self.paused.is_paused() {
+            return false;
+        }
+        let new = self
+            .relays
+            .add_with_reason(s, format_args!("referenced by {}", source));
+        if new {
+            self.time_last_discovery = self.clock.now();
+            self.queue_pending(url.clone());
+        }
+        if !self.streaming || self.graph_path.is_some() {
+            self.relays.add_referenced_by(&url, source);
+        }
+        self.stats.add_relay_hint(new);
+        new
+    }
+
+    fn queue_pending(&mut self, url: Url) {
+        if self.max_pending == 0 {
+            return;
+        }
+        if self.pending.len() >= self.max_pending {
+            self.pending.pop_front();
+            self.pending_dropped += 1;
+        }
+        self.pending.push_back(url);
     }
 
+    fn add_bootstrap_relays_if_needed(&mut self, bootstrap_relays: Vec<&str>) {
+        let added = self
+            .relays
+            .add_bootstrap(&bootstrap_relays, MAX_ACTIVE_RELAYS);
+        debug!("Added {added} bootstrap relays");
+    }
+
+    /// Select the relays to crawl, and update the pool to them: relays no
+    /// longer selected are removed, newly selected ones added, and the
+    /// others kept with their connection
     async fn add_some_relays(&mut self) -> Result<()> {
-        // remove all
-        loop {
-            let relays = self.relay_client.relays().await;
-            let relay_urls: Vec<&Url> = relays.keys().collect();
-            if relay_urls.is_empty() {
+        // The pending relays first, then a random pick
+        let candidates = if self.warm_start.is_empty() {
+            let n = self.pending.len().min(MAX_ACTIVE_RELAYS);
+            let mut candidates: Vec<Url> = self.pending.drain(..n).collect();
+            candidates.extend(
+                self.relays
+                    .get_some_random(self.relays.count(), &mut self.rng),
+            );
+            candidates
+        } else {
+            std::mem::take(&mut self.warm_start)
+        };
+        let mut some_relays = Vec::new();
+        for url in candidates {
+            if some_relays.len() >= MAX_ACTIVE_RELAYS {
                 break;
             }
-            self.relay_client
-                .remove_relay(relay_urls[0].to_string())
-                .await?;
+            if !some_relays.contains(&url)
+                && self.dialable(&url)
+                && self.meets_nip_requirements(&url).await
+            {
+                some_relays.push(url);
+            }
         }
-        let some_relays = self.relays.get_some(MAX_ACTIVE_RELAYS);
 
-        let args = CliArgs::parse();
-
-        let path = args.flag_git_dir.as_ref().map(|s| &s[..]).unwrap_or(".");
-        let repo = Repository::open(path)?;
-        let revwalk = repo.revwalk()?;
-        for commit in revwalk {
-            println!("\n\n\n\n\n{:?}\n\n\n\n", commit);
+        if let Some(args) = &self.git_args {
+            let path = args.flag_git_dir.as_ref().map(|s| &s[..]).unwrap_or(".");
+            let repo = Repository::open(path)?;
+            let revwalk = repo.revwalk()?;
+            for commit in revwalk {
+                println!("\n\n\n\n\n{:?}\n\n\n\n", commit);
+            }
         }
 
-        //async {
-        let opts = Options::new(); //.wait_for_send(true);
-        let app_keys = Keys::from_sk_str(APP_SECRET_KEY).unwrap();
-        let relay_client = Client::new_with_opts(&app_keys, opts);
-        //let _ = relay_client.publish_text_note(path, &[]).await;
-        //let _ = relay_client
-        //    .publish_text_note("relay_manager:1<--------------------------<<<<<", &[])
-        //    .await;
-        //let _ = relay_client
-        //    .publish_text_note("2<--------------------------<<<<<", &[])
-        //    .await;
-        //let _ = relay_client
-        //    .publish_text_note("3<--------------------------<<<<<", &[])
-        //    .await;
-        //let _ = relay_client
-        //    .publish_text_note("4<--------------------------<<<<<", &[])
-        //    .await;
-        let _ = relay_client.publish_text_note("#gnostr", &[]).await;
-        //};
-
-        for r in some_relays {
+        let pool: HashSet<Url> = self.relay_client.relays().await.into_keys().collect();
+        let selected: HashSet<&Url> = some_relays.iter().collect();
+        // A relay that fails is skipped, it must not end the crawl
+        for url in pool.iter().filter(|url| !selected.contains(url)) {
+            if let Err(e) = self.relay_client.remove_relay(url.to_string()).await {
+                debug!("Cannot remove relay {url}: {e}");
+                continue;
+            }
+            self.add_timeline(Some(url), TimelineEvent::Disconnected);
+        }
+        for r in some_relays.into_iter().filter(|url| !pool.contains(url)) {
             //self.relay_client.add_relay(r, None).await?;
-            self.relay_client.add_relay(r.clone(), None).await?;
+            let (proxy, opts) = self.relay_options(&r);
+            if let Err(e) = self
+                .relay_client
+                .add_relay_with_opts(r.clone(), proxy, opts)
+                .await
+            {
+                debug!("Cannot add relay {r}: {e}");
+                continue;
+            }
             //self.relay_client
             //    .publish_text_note("relay_manager:5<--------<<<<<<<<<", &[])
             //    .await?;
@@ -124,25 +1383,812 @@ impl RelayManager {
         Ok(())
     }
 
+    /// Crawl, then report and dump the relays found. The relays found so far
+    /// are also dumped if the crawl fails, or panics (when the manager is
+    /// dropped while unwinding).
     pub async fn run(&mut self, bootstrap_relays: Vec<&str>) -> Result<()> {
+        self.time_started = Instant::now();
+        let res = self.crawl(bootstrap_relays).await;
+        if let Err(e) = &res {
+            debug!("Crawl failed: {e}");
+        }
+        let dumped = self.finish();
+        res.and(dumped)
+    }
+
+    async fn crawl(&mut self, bootstrap_relays: Vec<&str>) -> Result<()> {
         self.add_bootstrap_relays_if_needed(bootstrap_relays);
-        self.add_some_relays().await?;
-        let some_relays = self.relays.get_some(MAX_ACTIVE_RELAYS);
-        for url in &some_relays {
-            self.relay_client.add_relay(url.to_string(), None).await?;
+        if self.info_survey {
+            self.survey().await;
+            if self.resolve_addrs {
+                self.resolve_relay_addrs().await;
+            }
+            return Ok(());
         }
+        if !self.discovery_expands_pool {
+            self.pinned = Some(self.relays.as_vec_sorted().into_iter().collect());
+        }
+        // Subscribe before connecting, each relay then sends the
+        // subscription as soon as it is connected
+        let now = Timestamp::now();
+        self.subscribe(now).await?;
+        self.add_some_relays().await?;
         self.connect().await?;
 
-        self.wait_and_handle_messages().await?;
+        self.wait_and_handle_messages(now).await?;
+        while self.widen_lookback(now).await {
+            self.wait_and_handle_messages(now).await?;
+        }
+        if self.full_sweep && !self.tail {
+            self.sweep(now).await?;
+        }
 
         debug!("STOPPED");
         debug!("======================================================");
         debug!("\n");
-        self.relays.dump_list();
+        // The connections are kept open until the publishing is done, so
+        // that a crawled relay is not dialed again
+        if self.fetch_info {
+            self.fetch_all_relay_info().await;
+        }
+        if self.resolve_addrs {
+            self.resolve_relay_addrs().await;
+        }
+        if let Some(relay) = self.monitor_relay.clone() {
+            self.record_connect_latencies();
+            let sent = self.publish_monitor_events(&relay).await?;
+            info!(
+                "Published {sent} NIP-66 relay discovery events to {}",
+                canonical_url(&relay)
+            );
+        }
+        self.disconnect().await?;
+        Ok(())
+    }
+
+    /// Connect to each known relay, a batch at a time, only to measure the
+    /// connection, and fetch its NIP-11 document meanwhile, see
+    /// [`Self::with_info_survey`]. The connections of a batch are closed
+    /// once all are made or timed out.
+    async fn survey(&mut self) {
+        let deadline = self
+            .max_runtime
+            .map(|max_runtime| self.time_started + max_runtime);
+        let urls = self.relays.as_vec_sorted();
+        for (i, batch) in urls.chunks(VALIDATION_BATCH_SIZE).enumerate() {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                self.stop_reason = Some(StopReason::Timeout);
+                break;
+            }
+            let fetches = self.spawn_relay_info_fetches(batch.to_vec());
+            for url in batch {
+                self.stats.add_connect_attempt();
+                self.dialed.insert(url.clone());
+            }
+            for (url, latency) in self.probe(batch.to_vec()).await {
+                if let Some(latency) = latency {
+                    self.relays.set_connect_latency(&url, latency);
+                    self.stats.add_connect_latency(latency);
+                }
+            }
+            self.record_relay_info_fetches(fetches).await;
+            debug!(
+                "Surveyed {} of {} relays",
+                i * VALIDATION_BATCH_SIZE + batch.len(),
+                urls.len()
+            );
+        }
+    }
+
+    /// Crawl the relays not dialed yet, a batch at a time, see
+    /// [`Self::with_full_sweep`]
+    async fn sweep(&mut self, subscribed: Timestamp) -> Result<()> {
+        loop {
+            if matches!(
+                self.stop_reason,
+                Some(StopReason::Timeout | StopReason::Shutdown | StopReason::RelayCap)
+            ) {
+                break;
+            }
+            let undialed: Vec<Url> = self
+                .relays
+                .as_vec_sorted()
+                .into_iter()
+                .filter(|url| {
+                    !self.dialed.contains(url)
+                        && !self.nip_rejected.contains(url)
+                        && !self.evicted.contains(url)
+                        && self.dialable(url)
+                })
+                .collect();
+            if undialed.is_empty() {
+                break;
+            }
+            debug!("Sweeping {} relays not dialed yet", undialed.len());
+            let dialed = self.dialed.len();
+            self.warm_start = undialed;
+            self.add_some_relays().await?;
+            self.connect().await?;
+            if self.dialed.len() == dialed {
+                // None of them could be dialed, e.g. all rejected
+                break;
+            }
+            self.wait_and_handle_messages(subscribed).await?;
+        }
+        Ok(())
+    }
+
+    /// Report and dump the relays
+    fn finish(&mut self) -> Result<()> {
+        self.dumped = true;
+        self.record_connect_latencies();
+        self.record_subscription_failures();
+        if let Some(store) = &mut self.reputation {
+            let now = Timestamp::now().as_u64();
+            for url in &self.dialed {
+                if let Some(meta) = self.relays.get(url) {
+                    store.record_run(url, meta, now, self.stale_after);
+                }
+            }
+        }
+        self.report();
+        self.summarize();
+        if let Some(path) = &self.graph_path {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            self.relays.write_dot(&mut file)?;
+        }
+        if let Some(path) = &self.timeline_path {
+            self.timeline.sort_by_key(|e| e.timestamp);
+            let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            serde_json::to_writer(file, &self.timeline)?;
+        }
+        if self.productive_only {
+            let removed = self.relays.retain("no events", |_, m| m.event_count > 0);
+            debug!("Leaving out {removed} relays without events");
+        }
+        if let Some(path) = &self.discovery_curve_path {
+            self.stats
+                .add_discovery_sample(self.time_started.elapsed(), self.relays.count());
+            let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            self.stats.write_discovery_csv(&mut file)?;
+        }
+        if let Some(path) = &self.npubs_path {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            for npub in self.processor.pubkeys().dump_npubs() {
+                writeln!(file, "{npub}")?;
+            }
+        }
+        if let Some(path) = &self.change_log_path {
+            let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            serde_json::to_writer(file, self.relays.change_log())?;
+        }
+        self.relays.shrink_to_fit();
+        let mut dump = self.relays.to_dump();
+        dump.created_at_hours = self.stats.created_at_hours.clone();
+        if let Some((dir, keep)) = &self.dump_rotation {
+            let path = dump.save_rotated(dir, *keep)?;
+            debug!("Saved the dump to {}", path.display());
+        }
+        if self.pretty_dump {
+            self.relays.dump_pretty();
+        } else {
+            dump.print_json()?;
+        }
 
         Ok(())
     }
 
+    /// Fetch the NIP-11 document of a relay, unless cached. Relays that time
+    /// out or don't serve a valid document yield `None`, with the reason
+    /// recorded.
+    pub async fn fetch_relay_info(&mut self, url: &Url) -> Option<RelayInformationDocument> {
+        let info = match self.cached_relay_info(url) {
+            Some(info) => Ok(info),
+            None => relay_info::fetch_relay_info(url, self.proxy, self.info_timeout).await,
+        };
+        self.record_relay_info(url, info.clone());
+        info.ok().map(|i| i.document)
+    }
+
+    fn cached_relay_info(&self, url: &Url) -> Option<RelayInfo> {
+        let ttl = self.info_cache_ttl?;
+        self.info_cache.get(url, ttl, Timestamp::now().as_u64())
+    }
+
+    fn record_relay_info(&mut self, url: &Url, info: std::result::Result<RelayInfo, SkipReason>) {
+        match &info {
+            Ok(info) => {
+                if self.info_cache_ttl.is_some() {
+                    self.info_cache.insert(url, info, Timestamp::now().as_u64());
+                }
+            }
+            Err(reason) => debug!("No relay information from {url}: {reason}"),
+        }
+        self.relays.set_info(url, info);
+    }
+
+    /// Fetch the NIP-11 documents of all discovered relays, concurrently,
+    /// except the cached ones
+    pub async fn fetch_all_relay_info(&mut self) {
+        let fetches = self.spawn_relay_info_fetches(self.relays.get_some(self.relays.count()));
+        self.record_relay_info_fetches(fetches).await;
+    }
+
+    /// Start fetching the NIP-11 documents of `urls` in the background,
+    /// except the cached ones, which are recorded right away
+    fn spawn_relay_info_fetches(
+        &mut self,
+        urls: Vec<Url>,
+    ) -> JoinSet<(Url, std::result::Result<RelayInfo, SkipReason>)> {
+        let mut fetches = JoinSet::new();
+        for url in urls {
+            if let Some(info) = self.cached_relay_info(&url) {
+                self.relays.set_info(&url, Ok(info));
+                continue;
+            }
+            let timeout = self.info_timeout;
+            let proxy = self.proxy;
+            fetches.spawn(async move {
+                let info = relay_info::fetch_relay_info(&url, proxy, timeout).await;
+                (url, info)
+            });
+        }
+        fetches
+    }
+
+    async fn record_relay_info_fetches(
+        &mut self,
+        mut fetches: JoinSet<(Url, std::result::Result<RelayInfo, SkipReason>)>,
+    ) {
+        while let Some(res) = fetches.join_next().await {
+            if let Ok((url, info)) = res {
+                self.record_relay_info(&url, info);
+            }
+        }
+    }
+
+    /// Resolve the hosts of all discovered relays, concurrently, see
+    /// [`Self::with_addr_resolution`]
+    async fn resolve_relay_addrs(&mut self) {
+        if self.proxy.is_some() {
+            debug!("Not resolving relay hosts through a proxy");
+            return;
+        }
+        let mut lookups = JoinSet::new();
+        for url in self.relays.get_some(self.relays.count()) {
+            let host = match url.host_str() {
+                Some(host) if !host.ends_with(".onion") => host.to_string(),
+                _ => continue,
+            };
+            let port = match url.port_or_known_default() {
+                Some(port) => port,
+                None => continue,
+            };
+            lookups.spawn(async move {
+                let addr = tokio::time::timeout(
+                    CONNECTIVITY_TIMEOUT,
+                    tokio::net::lookup_host((host, port)),
+                )
+                .await;
+                (url, addr)
+            });
+        }
+        while let Some(res) = lookups.join_next().await {
+            match res {
+                Ok((url, Ok(Ok(mut addrs)))) => {
+                    if let Some(addr) = addrs.next() {
+                        self.relays.set_addr(&url, addr);
+                    }
+                }
+                Ok((url, _)) => debug!("Cannot resolve {url}"),
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Add relays to the known set, e.g. from a relay list file
+    pub fn add_relays(&mut self, relays: &[&str]) {
+        for r in relays {
+            self.relays.add_with_reason(r, "relay list");
+        }
+    }
+
+    /// Seed the known relays from the `r` tags of a NIP-65 relay list event
+    /// (kind 10002). Returns the number of relays added.
+    pub fn bootstrap_from_relay_list(&mut self, event: &Event) -> Result<usize> {
+        if event.kind != Kind::RelayList {
+            return Err(format!("expected a relay list event, got {:?}", event.kind).into());
+        }
+        event.verify()?;
+        let mut count = 0;
+        for t in &event.tags {
+            if let Tag::RelayMetadata(url, marker) = t {
+                if self
+                    .relays
+                    .add_with_reason(url, format_args!("relay list event {}", event.id))
+                {
+                    count += 1;
+                }
+                if let Ok(url) = Url::parse(url) {
+                    self.relays.add_listed_role(&url, marker.as_deref());
+                }
+            }
+        }
+        debug!("Bootstrapped {count} relays from relay list {}", event.id);
+        Ok(count)
+    }
+
+    /// Try a short connection to each relay in the active pool, or to every
+    /// known relay if the pool is empty, and report which were reachable.
+    /// No subscription is made, and all probe connections are closed again.
+    pub async fn check_connectivity(&mut self) -> Vec<(Url, bool)> {
+        let mut urls: Vec<Url> = self.relay_client.relays().await.into_keys().collect();
+        if urls.is_empty() {
+            urls = self.relays.get_some(self.relays.count());
+        }
+        self.probe(urls)
+            .await
+            .into_iter()
+            .map(|(url, latency)| (url, latency.is_some()))
+            .collect()
+    }
+
+    /// Try a short connection to each of `urls`, and report how long
+    /// connecting took to the reachable ones
+    async fn probe(&self, urls: Vec<Url>) -> Vec<(Url, Option<Duration>)> {
+        let probe_client = Client::new_with_opts(
+            &self.relay_client.keys(),
+            Options::new().wait_for_connection(true),
+        );
+        for url in &urls {
+            let (proxy, opts) = self.relay_options(url);
+            if let Err(e) = probe_client
+                .add_relay_with_opts(url.to_string(), proxy, opts)
+                .await
+            {
+                debug!("Cannot probe {url}: {e}");
+            }
+        }
+
+        let mut checks = JoinSet::new();
+        for (url, relay) in probe_client.relays().await {
+            let permits = self.connect_permits.clone();
+            checks.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                let started = Instant::now();
+                let _ = tokio::time::timeout(CONNECTIVITY_TIMEOUT, relay.connect(true)).await;
+                let latency =
+                    (relay.status().await == RelayStatus::Connected).then(|| started.elapsed());
+                (url, latency)
+            });
+        }
+        let mut results = Vec::new();
+        while let Some(res) = checks.join_next().await {
+            if let Ok((url, latency)) = res {
+                debug!("{url} reachable: {}", latency.is_some());
+                results.push((url, latency));
+            }
+        }
+        let _ = probe_client.disconnect().await;
+
+        // Relays that could not even be added are unreachable
+        for url in urls {
+            if !results.iter().any(|(u, _)| *u == url) {
+                results.push((url, None));
+            }
+        }
+        results
+    }
+
+    /// NIP-66 relay discovery events (kind 30166) for the relays connected
+    /// to, signed with the app keys: the relay URL as `d` tag, the connection
+    /// time as `rtt-open` (milliseconds), the network as `n`, and from the
+    /// NIP-11 document, if fetched, the supported NIPs as `N`, whether
+    /// payment is required as `R`, and the document itself as content
+    pub fn build_monitor_events(&self) -> Vec<Event> {
+        let keys = self.relay_client.keys();
+        let tag =
+            |kind: &str, value: String| Tag::Generic(TagKind::Custom(kind.into()), vec![value]);
+        let mut events = Vec::new();
+        for url in self.relays.as_vec_sorted() {
+            let (meta, latency) = match self.relays.get(&url) {
+                Some(meta) => match meta.connect_latency {
+                    Some(latency) => (meta, latency),
+                    None => continue,
+                },
+                None => continue,
+            };
+            let network = match url.host_str() {
+                Some(host) if host.ends_with(".onion") => "tor",
+                _ => "clearnet",
+            };
+            let mut tags = vec![
+                Tag::Identifier(canonical_url(&url)),
+                tag("rtt-open", latency.as_millis().to_string()),
+                tag("n", network.to_string()),
+            ];
+            let mut content = String::new();
+            if let Some(Ok(info)) = &meta.info {
+                for nip in info.supported_nips.iter().flatten() {
+                    tags.push(tag("N", nip.to_string()));
+                }
+                content = serde_json::to_string(info).unwrap_or_default();
+            }
+            match meta.paid {
+                Some(true) => tags.push(tag("R", "payment".to_string())),
+                Some(false) => tags.push(tag("R", "!payment".to_string())),
+                None => {}
+            }
+            match EventBuilder::new(Kind::from(KIND_RELAY_DISCOVERY), content, &tags)
+                .to_event(&keys)
+            {
+                Ok(event) => events.push(event),
+                Err(e) => debug!("Cannot sign monitor event for {url}: {e}"),
+            }
+        }
+        events
+    }
+
+    /// Publish the [`Self::build_monitor_events`] to `relay`, returns the
+    /// number of events sent. They go through a separate client connected
+    /// only to `relay`, so the monitor relay never joins the crawl pool.
+    pub async fn publish_monitor_events(&self, relay: &Url) -> Result<usize> {
+        let events = self.build_monitor_events();
+        let monitor_client = Client::new_with_opts(
+            &self.relay_client.keys(),
+            Options::new().wait_for_connection(true),
+        );
+        let (proxy, opts) = self.relay_options(relay);
+        monitor_client
+            .add_relay_with_opts(relay.to_string(), proxy, opts)
+            .await?;
+        let connection = match monitor_client.relays().await.remove(relay) {
+            Some(connection) => connection,
+            None => return Ok(0),
+        };
+        let _ = tokio::time::timeout(CONNECTIVITY_TIMEOUT, connection.connect(true)).await;
+        let mut sent = 0;
+        for event in events {
+            match connection
+                .send_msg(ClientMessage::new_event(event), true)
+                .await
+            {
+                Ok(_) => sent += 1,
+                Err(e) => debug!("Cannot publish monitor event to {relay}: {e}"),
+            }
+        }
+        let _ = monitor_client.disconnect().await;
+        Ok(sent)
+    }
+
+    /// Diagnose a single relay: connect to it, measuring how long it takes,
+    /// and fetch its NIP-11 document
+    pub async fn probe_relay(&self, url: &Url) -> RelayProbe {
+        let probe_client = Client::new_with_opts(
+            &self.relay_client.keys(),
+            Options::new().wait_for_connection(true),
+        );
+        let mut connect_latency = None;
+        let (proxy, opts) = self.relay_options(url);
+        match probe_client
+            .add_relay_with_opts(url.to_string(), proxy, opts)
+            .await
+        {
+            Ok(()) => {
+                if let Some(relay) = probe_client.relays().await.remove(url) {
+                    let started = Instant::now();
+                    let _ = tokio::time::timeout(CONNECTIVITY_TIMEOUT, relay.connect(true)).await;
+                    if relay.status().await == RelayStatus::Connected {
+                        connect_latency = Some(started.elapsed());
+                    }
+                }
+            }
+            Err(e) => debug!("Cannot probe {url}: {e}"),
+        }
+        let _ = probe_client.disconnect().await;
+        let info = relay_info::fetch_relay_info(url, self.proxy, self.info_timeout).await;
+        RelayProbe {
+            url: canonical_url(url),
+            reachable: connect_latency.is_some(),
+            connect_latency_ms: connect_latency.map(|l| l.as_millis() as u64),
+            nip11_skipped: info.as_ref().err().map(|e| e.to_string()),
+            nip11: info.ok().map(|i| i.document),
+        }
+    }
+
+    /// Record the connection latencies measured so far
+    fn record_connect_latencies(&mut self) {
+        while let Ok((url, latency, connected_at)) = self.latency_receiver.try_recv() {
+            self.relays.set_connect_latency(&url, latency);
+            self.stats.add_connect_latency(latency);
+            self.timeline.push(TimelineEntry {
+                timestamp: connected_at,
+                relay: Some(canonical_url(&url)),
+                event: TimelineEvent::Connected,
+            });
+        }
+    }
+
+    /// Validate a list of relays without crawling: check which are reachable,
+    /// in batches, and fetch the NIP-11 document of the reachable ones
+    pub async fn validate_relays(&mut self, urls: Vec<Url>) -> Vec<RelayValidation> {
+        let mut results = Vec::new();
+        for batch in urls.chunks(VALIDATION_BATCH_SIZE) {
+            let mut fetches = JoinSet::new();
+            for (url, latency) in self.probe(batch.to_vec()).await {
+                let reachable = latency.is_some();
+                let proxy = self.proxy;
+                let timeout = self.info_timeout;
+                fetches.spawn(async move {
+                    let info = if reachable {
+                        Some(
+                            relay_info::fetch_relay_info(&url, proxy, timeout)
+                                .await
+                                .map(|i| i.document),
+                        )
+                    } else {
+                        None
+                    };
+                    RelayValidation::new(url, reachable, info)
+                });
+            }
+            while let Some(res) = fetches.join_next().await {
+                if let Ok(validation) = res {
+                    results.push(validation);
+                }
+            }
+        }
+        results.sort_by(|a, b| a.url.cmp(&b.url));
+        results
+    }
+
+    /// Log a one line summary of the crawl
+    fn summarize(&self) {
+        let crawled = self
+            .relays
+            .iter()
+            .filter(|(_, m)| m.connect_latency.is_some())
+            .count();
+        let unique = if self.stats.track_event_ids {
+            format!(
+                " ({} unique)",
+                format_count(self.stats.event_ids.len() as u64)
+            )
+        } else {
+            String::new()
+        };
+        info!(
+            "Crawled {} relays, discovered {}, processed {} events{} across {} kinds in {}.",
+            format_count(crawled as u64),
+            format_count(self.relays.count() as u64),
+            format_count(self.stats.count_events),
+            unique,
+            self.stats.kinds.len(),
+            format_elapsed(self.time_started.elapsed())
+        );
+    }
+
+    /// Log a report of the crawl
+    fn report(&self) {
+        if let Some(reason) = self.stop_reason {
+            info!("Stopped: {reason}");
+        }
+        if self.lookback > Duration::from_secs(PERIOD_START_PAST_SECS) {
+            info!(
+                "Lookback widened to {} for enough events",
+                format_elapsed(self.lookback)
+            );
+        }
+        info!(
+            "{} discovered relays still pending, {} dropped from the full queue",
+            self.pending.len(),
+            self.pending_dropped
+        );
+        let connected = self.relays.connected();
+        let no_eose: Vec<String> = connected
+            .iter()
+            .filter(|u| self.is_no_eose(u))
+            .map(canonical_url)
+            .collect();
+        if !no_eose.is_empty() {
+            info!(
+                "{} no-EOSE relays, events but no EOSE within {}: {}",
+                no_eose.len(),
+                format_elapsed(self.no_eose_window),
+                no_eose.join(", ")
+            );
+        }
+        info!(
+            "Relays: {} discovered, {} connected, {} of them sent EOSE",
+            self.relays.count(),
+            connected.len(),
+            connected
+                .iter()
+                .filter(|u| self.relays.get(u).map(|m| m.eose).unwrap_or(false))
+                .count()
+        );
+        if let Some(max_relays) = self.max_relays {
+            info!(
+                "Relay cap {}: {} of {} relays, {} relay hints ignored",
+                if self.relay_cap_reached() {
+                    "reached"
+                } else {
+                    "not reached"
+                },
+                self.relays.count(),
+                max_relays,
+                self.stats.count_capped_relay_hints
+            );
+        }
+        if self.allowlist_mode {
+            let outside = self.relays.outside_allowlist();
+            info!(
+                "Allowlist: {} other relays referenced {} times",
+                outside.len(),
+                outside.values().sum::<u64>()
+            );
+        }
+        info!(
+            "Discovered {} relays on {} hosts",
+            self.relays.count(),
+            self.relays.hosts().len()
+        );
+        if let Some(ratio) = self.stats.connect_success_ratio() {
+            info!(
+                "Connections: {} of {} attempts succeeded ({:.1}%)",
+                self.stats.connect_latencies.len(),
+                self.stats.count_connect_attempts,
+                ratio * 100.0
+            );
+        }
+        if let (Some(p50), Some(p90), Some(p99)) = (
+            self.stats.connect_latency_percentile(50.0),
+            self.stats.connect_latency_percentile(90.0),
+            self.stats.connect_latency_percentile(99.0),
+        ) {
+            info!(
+                "Connect latency: p50 {:.1}s, p90 {:.1}s, p99 {:.1}s",
+                p50.as_secs_f64(),
+                p90.as_secs_f64(),
+                p99.as_secs_f64()
+            );
+        }
+        let threshold = Timestamp::now()
+            .as_u64()
+            .saturating_sub(self.stale_after.as_secs());
+        let stale = self.relays.stale(threshold);
+        if !stale.is_empty() {
+            info!(
+                "{} stale relays, no events in the last {}:",
+                stale.len(),
+                format_elapsed(self.stale_after)
+            );
+            for url in stale {
+                info!("  {}", canonical_url(&url));
+            }
+        }
+        if !self.nip_rejected.is_empty() {
+            info!(
+                "{} relays not connected to, NIP requirements not met",
+                self.nip_rejected.len()
+            );
+        }
+        if self.fetch_info {
+            let search = self.relays.search_capable_relays();
+            info!("{} relays support NIP-50 search:", search.len());
+            for url in search {
+                info!("  {}", canonical_url(&url));
+            }
+            let free = self.relays.free_relays();
+            info!("{} free relays:", free.len());
+            for url in free {
+                info!("  {}", canonical_url(&url));
+            }
+            let paid = self.relays.paid_relays();
+            info!("{} paid relays:", paid.len());
+            for url in paid {
+                info!("  {}", canonical_url(&url));
+            }
+        }
+        let mut eose_latencies: Vec<(&Url, &Duration)> = self.stats.eose_latencies.iter().collect();
+        eose_latencies.sort();
+        for (url, latency) in eose_latencies {
+            info!(
+                "EOSE from {} after {:.1}s",
+                canonical_url(url),
+                latency.as_secs_f64()
+            );
+        }
+        for (addr, urls) in self.relays.addr_clusters() {
+            let urls: Vec<String> = urls.iter().map(canonical_url).collect();
+            info!("{} relays at {addr}: {}", urls.len(), urls.join(", "));
+        }
+        for cluster in clones::clusters(&self.stats.event_signatures, self.clone_threshold) {
+            let urls: Vec<String> = cluster.iter().map(canonical_url).collect();
+            info!(
+                "Relay clones (similarity at least {:.2}): {}",
+                self.clone_threshold,
+                urls.join(", ")
+            );
+        }
+        if let Some(search) = &self.search {
+            let mut matches: Vec<(&Url, &u64)> = self.stats.search_matches.iter().collect();
+            matches.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            info!("{} relays matched the search {:?}", matches.len(), search);
+            for (url, count) in matches {
+                info!("Search matches from {}: {}", canonical_url(url), count);
+            }
+        }
+        let mut flood_drops: Vec<(&Url, &u64)> = self.stats.flood_drops.iter().collect();
+        flood_drops.sort();
+        for (url, dropped) in flood_drops {
+            info!(
+                "Flooding relay {}: dropped {} events over the rate cap",
+                canonical_url(url),
+                dropped
+            );
+        }
+        let mut subscription_failures: Vec<(&Url, &String)> =
+            self.stats.subscription_failures.iter().collect();
+        subscription_failures.sort();
+        for (url, reason) in subscription_failures {
+            info!(
+                "Relay {}: subscription failed or rejected: {}",
+                canonical_url(url),
+                reason
+            );
+        }
+        let mut event_panics: Vec<(&Url, &u64)> = self.stats.event_panics.iter().collect();
+        event_panics.sort();
+        for (url, panics) in event_panics {
+            info!(
+                "Relay {}: handling {} events panicked, skipped",
+                canonical_url(url),
+                panics
+            );
+        }
+        if self.relays.placeholders() > 0 {
+            info!(
+                "Placeholders: {} references to placeholder relay URLs rejected",
+                self.relays.placeholders()
+            );
+        }
+        let mut oversized_drops: Vec<(&Url, &u64)> = self.stats.oversized_drops.iter().collect();
+        oversized_drops.sort();
+        for (url, dropped) in oversized_drops {
+            info!(
+                "Relay {}: dropped {} events over the size limit",
+                canonical_url(url),
+                dropped
+            );
+        }
+        if self.stats.notifications_missed > 0 {
+            info!(
+                "Missed {} notifications, the channel overflowed; see --notification-buffer",
+                self.stats.notifications_missed
+            );
+        }
+        info!("Received {} bytes of events", self.stats.total_bytes);
+        let pubkeys = self.processor.pubkeys();
+        info!(
+            "Pubkeys: {} distinct, {} authors, {} referenced in contact lists",
+            format_count(pubkeys.count() as u64),
+            format_count(pubkeys.author_count() as u64),
+            format_count(pubkeys.referenced_count() as u64)
+        );
+        for (hour, count) in &self.stats.created_at_hours {
+            info!("Events created in hour from {hour}: {count}");
+        }
+        if let Some(saturation) = self.stats.relay_hint_saturation() {
+            info!(
+                "Relay hints: {} new, {} already known ({:.1}% saturation)",
+                self.stats.count_new_relay_hints,
+                self.stats.count_known_relay_hints,
+                saturation * 100.0
+            );
+        }
+    }
+
     async fn connect(&mut self) -> Result<()> {
         let relays = self.relay_client.relays().await;
         debug!("Connecting to {} relays ...", relays.len());
@@ -151,33 +2197,221 @@ impl RelayManager {
         }
         debug!("\n");
         // Warning: error is not handled here, should check back status
-        self.relay_client.connect().await;
+        for relay in relays.into_values() {
+            // Kept across a reconnect, and still connected
+            if matches!(
+                relay.status().await,
+                RelayStatus::Connected | RelayStatus::Connecting
+            ) {
+                continue;
+            }
+            self.connect_relay(relay, false);
+        }
         debug!("Connected");
         Ok(())
     }
 
+    /// Connect to a relay in the background, once a connection permit is
+    /// available, and measure how long connecting took. With `subscribe`,
+    /// the crawl subscription is sent once connected, for relays added after
+    /// the pool subscribed.
+    fn connect_relay(&mut self, relay: Relay, subscribe: bool) {
+        let search = self.search_request(&relay.url());
+        self.stats.add_connect_attempt();
+        self.dialed.insert(relay.url());
+        self.add_timeline(Some(&relay.url()), TimelineEvent::Connecting);
+        let permits = self.connect_permits.clone();
+        let latencies = self.latency_sender.clone();
+        let subscription_failures = self.subscription_failure_sender.clone();
+        tokio::spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            let started = Instant::now();
+            relay.connect(true).await;
+            if relay.status().await == RelayStatus::Connected {
+                let _ = latencies.send((relay.url(), started.elapsed(), unix_millis()));
+                if subscribe {
+                    if let Err(e) = relay.subscribe(false).await {
+                        debug!("Cannot subscribe to {}: {e}", relay.url());
+                        let _ = subscription_failures.send((relay.url(), e.to_string()));
+                    }
+                }
+                if let Some(search) = search {
+                    if let Err(e) = relay.send_msg(search, false).await {
+                        debug!("Cannot send the search to {}: {e}", relay.url());
+                    }
+                }
+            } else {
+                debug!("Cannot connect to {}", relay.url());
+            }
+        });
+    }
+
+    /// The search subscription for `url`, if searching and the relay
+    /// supports NIP-50
+    fn search_request(&self, url: &Url) -> Option<ClientMessage> {
+        let search = self.search.as_ref()?;
+        if !self.relays.get(url)?.supports_nip(50) {
+            return None;
+        }
+        let filters = self
+            .subscription_filters(Timestamp::now())
+            .into_iter()
+            .map(|filter| filter.search(search))
+            .collect();
+        Some(ClientMessage::new_req(
+            SubscriptionId::new(SEARCH_SUBSCRIPTION_ID),
+            filters,
+        ))
+    }
+
     async fn disconnect(&mut self) -> Result<()> {
+        for url in self.relay_client.relays().await.into_keys() {
+            self.add_timeline(Some(&url), TimelineEvent::Disconnected);
+        }
         self.relay_client.disconnect().await?;
         debug!("Disconnected");
         Ok(())
     }
 
-    async fn subscribe(&mut self, time_start: Timestamp, time_end: Timestamp) -> Result<()> {
-        self.relay_client
-            .subscribe(vec![Filter::new()
+    /// One filter per distinct lookback, each covering the kinds crawled
+    /// over that window, up to `now`; split when there are more kinds than
+    /// fit in a filter
+    fn subscription_filters(&self, now: Timestamp) -> Vec<Filter> {
+        let mut windows = BTreeMap::<Duration, Vec<Kind>>::new();
+        windows.insert(self.lookback, self.default_window_kinds());
+        let metadata = self.metadata_discovery.then_some(Kind::Metadata);
+        for (kind, lookback) in &self.kind_lookbacks {
+            // Relays-only crawls ignore the lookbacks of the other kinds
+            if !self.relays_only || RELAY_KINDS.contains(kind) || metadata == Some(*kind) {
+                windows.entry(*lookback).or_default().push(*kind);
+            }
+        }
+
+        windows
+            .into_iter()
+            .filter(|(_, kinds)| !kinds.is_empty())
+            .flat_map(|(lookback, kinds)| {
+                if self.tail {
+                    self.window_filters(&kinds, now, None)
+                } else {
+                    self.window_filters(&kinds, now - lookback, Some(now))
+                }
+            })
+            .collect()
+    }
+
+    /// The kinds crawled over the default period, those without a lookback
+    /// of their own
+    fn default_window_kinds(&self) -> Vec<Kind> {
+        let kinds: &[Kind] = if self.relays_only {
+            &RELAY_KINDS
+        } else {
+            &CRAWL_KINDS
+        };
+        let metadata = self.metadata_discovery.then_some(Kind::Metadata);
+        kinds
+            .iter()
+            .chain(metadata.iter())
+            .filter(|kind| !self.kind_lookbacks.contains_key(kind))
+            .copied()
+            .collect()
+    }
+
+    /// Filters for `kinds` from `since`, up to `until` if set
+    fn window_filters(
+        &self,
+        kinds: &[Kind],
+        since: Timestamp,
+        until: Option<Timestamp>,
+    ) -> Vec<Filter> {
+        kinds
+            .chunks(self.max_filter_kinds)
+            .map(|kinds| {
                 // .pubkey(keys.public_key())
-                // .kind(Kind::RecommendRelay)
-                .kinds(vec![Kind::ContactList, Kind::RecommendRelay])
-                .since(time_start)
-                .until(time_end)])
+                let mut filter = Filter::new().kinds(kinds.to_vec()).since(since);
+                if let Some(until) = until {
+                    filter = filter.until(until);
+                }
+                if let Some(limit) = self.per_relay_limit {
+                    filter = filter.limit(limit);
+                }
+                filter
+            })
+            .collect()
+    }
+
+    /// If the crawl stopped with too few events, see
+    /// [`Self::with_adaptive_lookback`], widen the default period and set
+    /// the whole crawl subscription again, so that the relays connecting
+    /// later get it too. Returns whether it did.
+    async fn widen_lookback(&mut self, now: Timestamp) -> bool {
+        let (min_events, max_lookback) = match self.adaptive_lookback {
+            Some(adaptive_lookback) => adaptive_lookback,
+            None => return false,
+        };
+        if self.tail
+            || !matches!(
+                self.stop_reason,
+                Some(StopReason::AllEose | StopReason::Silence)
+            )
+            || self.events_found() >= min_events
+            || self.lookback >= max_lookback
+        {
+            return false;
+        }
+        let previous = self.lookback;
+        self.lookback = (previous * 2).min(max_lookback);
+        debug!(
+            "Only {} events, widening the lookback from {} to {}",
+            self.events_found(),
+            format_elapsed(previous),
+            format_elapsed(self.lookback)
+        );
+        if self.default_window_kinds().is_empty() {
+            return false;
+        }
+        self.relay_client
+            .subscribe(self.subscription_filters(now))
             .await;
+        self.stop_reason = None;
+        true
+    }
+
+    /// Number of events received, each counted once if event ids are tracked
+    fn events_found(&self) -> u64 {
+        if self.stats.track_event_ids {
+            self.stats.event_ids.len() as u64
+        } else {
+            self.stats.count_events
+        }
+    }
+
+    /// Set the crawl subscription. nostr-sdk sends it to every relay of the
+    /// pool once connected, including relays added later, so this is done
+    /// before the relays are added: sending it to relays still connecting
+    /// would race with that, and send it twice to the fast ones.
+    async fn subscribe(&mut self, now: Timestamp) -> Result<()> {
+        let filters = self.subscription_filters(now);
+        self.relay_client.subscribe(filters).await;
         debug!("Subscribed to relay events",);
-        self.relay_client
-            .publish_text_note(format!("{}", time_start), &[])
-            .await?;
-        self.relay_client
-            .publish_text_note(format!("{}", time_end), &[])
-            .await?;
+        Ok(())
+    }
+
+    /// Publish the time window of the subscription
+    async fn publish_subscription_window(&mut self, now: Timestamp) -> Result<()> {
+        let filters = self.subscription_filters(now);
+        for filter in &filters {
+            if let Some(time_start) = filter.since {
+                self.relay_client
+                    .publish_text_note(format!("{}", time_start), &[])
+                    .await?;
+            }
+        }
+        if !self.tail {
+            self.relay_client
+                .publish_text_note(format!("{}", now), &[])
+                .await?;
+        }
         Ok(())
     }
 
@@ -188,14 +2422,20 @@ impl RelayManager {
     }
 
     async fn reconnect(&mut self) -> Result<()> {
+        if self.paused.is_paused() {
+            return Ok(());
+        }
         let connected_relays = self.relay_client.relays().await.len();
-        let available_relays = self.relays.count();
+        let available_relays = match &self.pinned {
+            Some(pinned) => pinned.len(),
+            None => self.relays.count(),
+        };
         if connected_relays < MAX_ACTIVE_RELAYS && available_relays > connected_relays {
             debug!(
                 "connected_relays={} available_relays={}",
                 connected_relays, available_relays
             );
-            self.disconnect().await?;
+            self.add_timeline(None, TimelineEvent::Reconnect);
             self.add_some_relays().await?;
             self.connect().await?;
             self.relay_client
@@ -208,79 +2448,357 @@ impl RelayManager {
         Ok(())
     }
 
-    async fn wait_and_handle_messages(&mut self) -> Result<()> {
+    async fn wait_and_handle_messages(&mut self, subscribed: Timestamp) -> Result<()> {
         // Keep track of relays with EOSE sent
         let mut eose_relays = HashSet::<Url>::new();
 
-        let now = Timestamp::now();
-        let period_end = now;
-        let period_start = period_end - Duration::from_secs(PERIOD_START_PAST_SECS);
-        self.subscribe(period_start, period_end).await?;
+        self.publish_subscription_window(subscribed).await?;
+        // Includes the connection time, the subscription is sent once connected
+        let now = Instant::now();
+        for url in self.relay_client.relays().await.into_keys() {
+            self.subscribed_at.insert(url, now);
+        }
 
-        let mut notifications = self.relay_client.notifications();
-        while let Ok(notification) = notifications.recv().await {
+        let (evicted_sender, mut evicted_receiver) = mpsc::channel::<Vec<Url>>(16);
+        let health_task = self.health_check.map(|(interval, stuck_after)| {
+            tokio::spawn(poll_relay_health(
+                self.relay_client.clone(),
+                interval,
+                stuck_after,
+                evicted_sender,
+            ))
+        });
+
+        let time_started = Instant::now();
+        let mut progress_ticker = self
+            .progress_interval
+            .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+        let mut discovery_ticker = self.discovery_curve_path.is_some().then(|| {
+            tokio::time::interval_at(
+                tokio::time::Instant::now() + DISCOVERY_SAMPLE_INTERVAL,
+                DISCOVERY_SAMPLE_INTERVAL,
+            )
+        });
+        let mut no_eose_ticker = (self.exclude_no_eose && !self.tail).then(|| {
+            let period = self.no_eose_window.max(Duration::from_secs(1));
+            tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+        });
+
+        let interrupted = tokio::signal::ctrl_c();
+        tokio::pin!(interrupted);
+        let out_of_time =
+            sleep_until(self.max_runtime.map(|max_runtime| {
+                tokio::time::Instant::from_std(self.time_started) + max_runtime
+            }));
+        tokio::pin!(out_of_time);
+
+        let (processor_sender, processor_task) = match self.processor_buffer_size {
+            Some(size) => {
+                let (sender, receiver) = mpsc::channel::<(Url, Event)>(size);
+                let processor = std::mem::take(&mut self.processor);
+                let task =
+                    tokio::spawn(process_events(processor, receiver, Processor::handle_event));
+                (Some(sender), Some(task))
+            }
+            None => (None, None),
+        };
+
+        let mut res = Ok(());
+        self.time_last_discovery = self.clock.now();
+        let (mut notifications, forward_task) =
+            Notifications::new(self.relay_client.notifications(), self.notification_buffer);
+        loop {
+            if self.paused.is_paused() {
+                self.time_last_discovery = self.clock.now();
+            }
+            let discovery_deadline = self
+                .discovery_idle_left()
+                .map(|left| tokio::time::Instant::now() + left);
+            let notification = tokio::select! {
+                res = notifications.recv() => match res {
+                    Some(Ok(notification)) => notification,
+                    Some(Err(missed)) => {
+                        debug!("Missed {missed} notifications, the channel overflowed");
+                        self.stats.notifications_missed += missed;
+                        continue;
+                    }
+                    None => {
+                        self.stop_reason = Some(StopReason::Shutdown);
+                        break;
+                    }
+                },
+                Some(dead) = evicted_receiver.recv() => {
+                    self.evict_and_backfill(dead).await;
+                    continue;
+                }
+                _ = &mut interrupted => {
+                    debug!("STOPPING; Interrupted");
+                    self.stop_reason = Some(StopReason::Shutdown);
+                    break;
+                }
+                _ = &mut out_of_time => {
+                    debug!("STOPPING; Maximum runtime reached");
+                    self.stop_reason = Some(StopReason::Timeout);
+                    break;
+                }
+                _ = sleep_until(discovery_deadline) => {
+                    debug!("STOPPING; No new relays discovered for a while");
+                    self.stop_reason = Some(StopReason::DiscoveryIdle);
+                    break;
+                }
+                _ = tick(&mut discovery_ticker) => {
+                    self.stats
+                        .add_discovery_sample(self.time_started.elapsed(), self.relays.count());
+                    continue;
+                }
+                _ = tick(&mut no_eose_ticker) => {
+                    // Relays turning no-EOSE may complete the crawl without
+                    // any further EOSE
+                    if self.eose_complete(eose_relays.len()).await {
+                        self.stop_reason = Some(StopReason::AllEose);
+                        break;
+                    }
+                    continue;
+                }
+                _ = tick(&mut progress_ticker) => {
+                    info!(
+                        "Discovered {} relays after {}, {} pending",
+                        self.relays.count(),
+                        format_elapsed(time_started.elapsed()),
+                        self.pending.len()
+                    );
+                    continue;
+                }
+            };
             debug!("relaynotif {:?}", notification);
             match notification {
-                RelayPoolNotification::Event(_url, event) => {
-                    self.handle_event(&event);
-                    // invoke callback
-                    self.processor.handle_event(&event);
+                RelayPoolNotification::Event(url, event) if self.is_oversized(&event) => {
+                    self.stats.add_oversized_drop(&url);
+                    trace!("Dropping oversized event {} from {url}", event.id);
+                }
+                RelayPoolNotification::Event(url, event) if self.over_event_rate(&url) => {
+                    self.stats.add_flood_drop(&url);
+                    trace!("Dropping event {} from flooding relay {url}", event.id);
+                }
+                RelayPoolNotification::Event(url, event) => {
+                    self.relays.add_event_from(&url, event.created_at.as_u64());
+                    let new = self.stats.add_event(event.id, event.kind);
+                    if self.relays_only {
+                        self.handle_event_isolated(&url, &event);
+                    } else {
+                        self.stats.add_event_from(&url, &event.id);
+                        self.stats.add_bytes(event.as_json().len());
+                        self.stats.add_event_time(event.created_at.as_u64());
+                        self.handle_event_isolated(&url, &event);
+                        // invoke callback
+                        if !self.is_duplicate(&event, new) {
+                            match &processor_sender {
+                                Some(sender) => {
+                                    let _ = sender.send((url, event)).await;
+                                }
+                                None => {
+                                    let processor = &mut self.processor;
+                                    if let Err(e) = catch_panic(|| processor.handle_event(&event)) {
+                                        warn!(
+                                            "Processing event {} from {url} panicked: {e}",
+                                            event.id
+                                        );
+                                        self.stats.add_event_panic(&url);
+                                    }
+                                }
+                            }
+                        }
+                    }
                 }
                 RelayPoolNotification::Message(url, relaymsg) => match relaymsg {
                     RelayMessage::EndOfStoredEvents(_sub_id) => {
-                        eose_relays.insert(url.clone());
-                        let n1 = eose_relays.len();
-                        let n2 = self.relay_client.relays().await.len();
-                        let mut n_connected = 0;
-                        let mut n_connecting = 0;
-                        let relays = self.relay_client.relays().await;
-                        for relay in relays.values() {
-                            match relay.status().await {
-                                RelayStatus::Connected => n_connected += 1,
-                                RelayStatus::Connecting => n_connecting += 1,
-                                _ => {}
+                        self.add_timeline(Some(&url), TimelineEvent::Eose);
+                        self.relays.set_eose(&url);
+                        if eose_relays.insert(url.clone()) {
+                            if let Some(subscribed_at) = self.subscribed_at.get(&url) {
+                                self.stats
+                                    .add_eose_latency(url.clone(), subscribed_at.elapsed());
                             }
                         }
-                        debug!("Received EOSE from {url}, total {n1} ({n2} relays, {n_connected} connected {n_connecting} connecting)");
-
-                        // Check for stop: All connected/connecting relays have signalled EOSE, or
-                        if n1 >= (n_connected + n_connecting) && (n_connected + n_connecting > 0) {
-                            debug!("STOPPING; All relays signalled EOSE ({n1})");
+                        debug!("Received EOSE from {url}");
+                        // Check for stop: Enough connected/connecting relays have signalled EOSE, or
+                        if self.eose_complete(eose_relays.len()).await {
+                            self.stop_reason = Some(StopReason::AllEose);
                             break;
                         }
                     }
                     RelayMessage::Event {
-                        subscription_id: _,
+                        subscription_id,
                         event: _,
-                    } => {}
+                    } => {
+                        if subscription_id == SubscriptionId::new(SEARCH_SUBSCRIPTION_ID) {
+                            self.stats.add_search_match(&url);
+                        }
+                    }
+                    RelayMessage::Auth { challenge } => {
+                        if let Err(e) = self.authenticate(&url, challenge).await {
+                            debug!("Cannot authenticate to {url}: {e}");
+                        }
+                    }
+                    RelayMessage::Notice { message }
+                    | RelayMessage::Ok {
+                        status: false,
+                        message,
+                        ..
+                    } if is_rate_limited(&message) => {
+                        self.back_off(&url, &message).await;
+                    }
+                    // nostr-sdk 0.19 drops CLOSED messages, relays rejecting
+                    // the subscription can only be told by their notice
+                    RelayMessage::Notice { message }
+                        if !eose_relays.contains(&url) && is_subscription_rejection(&message) =>
+                    {
+                        debug!("{url} rejected the subscription: {message}");
+                        self.stats.add_subscription_failure(&url, &message);
+                    }
                     _ => {
                         debug!("{{\"{:?}\":\"{url}\"}}", relaymsg);
                     }
                 },
-                RelayPoolNotification::Shutdown => break,
+                RelayPoolNotification::Shutdown => {
+                    self.stop_reason = Some(StopReason::Shutdown);
+                    break;
+                }
             }
+            self.record_nip05_relays();
+            self.record_subscription_failures();
             // Check for stop: There was no event in the last few seconds, and there were some EOSE already
-            let last_age = self.get_last_event_ago();
-            let n1 = eose_relays.len();
-            if last_age > 20 && n1 >= 2 {
+            if self.silence_reached(eose_relays.len()) {
                 debug!(
                     "STOPPING; There were some EOSE-s, and no events in the past {} secs",
-                    last_age
+                    self.get_last_event_ago().as_secs()
                 );
+                self.stop_reason = Some(StopReason::Silence);
+                break;
+            }
+            // Check for stop: The relay cap has been reached, nothing more to discover
+            if self.relay_cap_reached() {
+                debug!("STOPPING; Relay cap reached ({})", self.relays.count());
+                self.stop_reason = Some(StopReason::RelayCap);
                 break;
             }
 
-            self.reconnect().await?;
+            if let Err(e) = self.reconnect().await {
+                self.stop_reason = Some(StopReason::AllRelaysFailed);
+                res = Err(e);
+                break;
+            }
+        }
+        if let Some(health_task) = health_task {
+            health_task.abort();
+        }
+        if let Some(forward_task) = forward_task {
+            forward_task.abort();
+        }
+        // Let the processor finish the queued events, and take it back
+        drop(processor_sender);
+        if let Some(processor_task) = processor_task {
+            if let Ok((processor, panics)) = processor_task.await {
+                self.processor = processor;
+                for (url, count) in panics {
+                    *self.stats.event_panics.entry(url).or_insert(0) += count;
+                }
+            }
         }
+        res?;
         self.unsubscribe().await?;
-        self.disconnect().await?;
         Ok(())
     }
 
-    fn handle_event(&mut self, event: &Event) {
+    /// Remove `dead` relays from the pool and replace them with other known
+    /// relays, unless paused. Failures are logged and skipped, they don't end
+    /// the crawl.
+    async fn evict_and_backfill(&mut self, dead: Vec<Url>) {
+        for url in dead {
+            debug!("Evicting unhealthy relay {url}");
+            if let Err(e) = self.relay_client.remove_relay(url.to_string()).await {
+                debug!("Cannot remove relay {url}: {e}");
+            }
+            self.add_timeline(Some(&url), TimelineEvent::Evicted);
+            self.evicted.insert(url);
+        }
+        if self.paused.is_paused() {
+            return;
+        }
+        let pool = self.relay_client.relays().await;
+        let missing = MAX_ACTIVE_RELAYS.saturating_sub(pool.len());
+        let candidates: Vec<Url> = self
+            .relays
+            .get_some_random(self.relays.count(), &mut self.rng)
+            .into_iter()
+            .filter(|u| !pool.contains_key(u) && !self.evicted.contains(u))
+            .collect();
+        let mut added = 0;
+        for url in candidates {
+            if added >= missing {
+                break;
+            }
+            if !self.dialable(&url) || !self.meets_nip_requirements(&url).await {
+                continue;
+            }
+            added += 1;
+            debug!("Backfilling with relay {url}");
+            let (proxy, opts) = self.relay_options(&url);
+            if let Err(e) = self
+                .relay_client
+                .add_relay_with_opts(url.to_string(), proxy, opts)
+                .await
+            {
+                debug!("Cannot backfill with relay {url}: {e}");
+                continue;
+            }
+            if let Some(relay) = self.relay_client.relays().await.remove(&url) {
+                // Includes the connection time, the subscription is sent once connected
+                self.subscribed_at.insert(url.clone(), Instant::now());
+                self.connect_relay(relay, true);
+            }
+        }
+    }
+
+    /// Add relay hints found in `e` and `a` tags, pointing to where the
+    /// referenced events live
+    fn add_reference_relay_hints(&mut self, source: &Url, event: &Event) {
+        for t in &event.tags {
+            match t {
+                Tag::Event(_id, Some(relay_url), _marker) => {
+                    trace!("    e {relay_url}");
+                    let _ = self.add_discovered_relay(source, relay_url);
+                }
+                Tag::A { relay_url, .. } => {
+                    trace!("    a {relay_url}");
+                    let _ = self.add_discovered_relay(source, relay_url);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Handle an event as [`RelayManager::handle_event`] does, but a panic,
+    /// e.g. on a malformed event from a buggy relay, is logged and counted
+    /// instead of ending the crawl
+    fn handle_event_isolated(&mut self, source: &Url, event: &Event) {
+        if let Err(e) = catch_panic(|| self.handle_event(source, event)) {
+            warn!("Handling event {} from {source} panicked: {e}", event.id);
+            self.stats.add_event_panic(source);
+        }
+    }
+
+    fn handle_event(&mut self, source: &Url, event: &Event) {
+        self.add_reference_relay_hints(source, event);
         match event.kind {
             Kind::Metadata => {
                 debug!("{:?}", event.kind);
+                for relay in metadata_relays(&event.content) {
+                    let _ = self.add_discovered_relay(source, &relay);
+                }
+                if self.metadata_discovery {
+                    self.resolve_nip05(source, event);
+                }
             }
             Kind::TextNote => {
                 debug!("{:?}", event.kind);
@@ -346,31 +2864,38 @@ impl RelayManager {
                 debug!("{:?}", event.kind);
                 self.update_event_time();
                 // count p tags
-                let mut cnt = 0;
-                for t in &event.tags {
-                    //if let Tag::PubKey(_pk, Some(ss)) = t {
-                    //  state.pubkeys.add(pk);
-                    //if let Some(ss) = s {
-                    //debug!("    {ss}");
-                    //let _ = self.relays.add(ss);
-                    //}
-                    cnt += 1;
-                    //}
-                }
+                //for t in &event.tags {
+                //if let Tag::PubKey(_pk, Some(ss)) = t {
+                //  state.pubkeys.add(pk);
+                //if let Some(ss) = s {
+                //debug!("    {ss}");
+                //let _ = self.relays.add(ss);
+                //}
+                //}
+                //}
             }
             Kind::RelayList => {
                 debug!("{:?}", event.kind);
+                self.update_event_time();
+                for t in &event.tags {
+                    if let Tag::RelayMetadata(url, marker) = t {
+                        let _ = self.add_discovered_relay(source, url);
+                        if let Ok(url) = Url::parse(url) {
+                            self.relays.add_listed_role(&url, marker.as_deref());
+                        }
+                    }
+                }
             }
-            Kind::Replaceable(u16) => {
+            Kind::Replaceable(_) => {
                 debug!("{:?}", event.kind);
             }
-            Kind::Ephemeral(u16) => {
+            Kind::Ephemeral(_) => {
                 debug!("{:?}", event.kind);
             }
-            Kind::ParameterizedReplaceable(u16) => {
+            Kind::ParameterizedReplaceable(_) => {
                 debug!("{:?}", event.kind);
             }
-            Kind::Custom(u64) => {
+            Kind::Custom(_) => {
                 debug!("{:?}", event.kind);
             }
             Kind::ContactList => {
@@ -378,11 +2903,11 @@ impl RelayManager {
                 // count p tags
                 let mut count = 0;
                 for t in &event.tags {
-                    if let Tag::PubKey(pk, Some(ss)) = t {
+                    if let Tag::PubKey(_pk, Some(ss)) = t {
                         //state.pubkeys.add(pk);
                         //if let Some(ss) = s {
                         debug!("    {ss}");
-                        let _ = self.relays.add(ss);
+                        let _ = self.add_discovered_relay(source, ss);
                         let _pub_future = self.relay_client.publish_text_note(ss.to_string(), &[]);
                         //}
                         debug!("    {}", count);
@@ -393,26 +2918,825 @@ impl RelayManager {
             Kind::RecommendRelay => {
                 self.update_event_time();
                 debug!("\n393:Relay(s): {}\n", event.content);
-                let _ = self.relays.add(&event.content);
+                let _ = self.add_discovered_relay(source, &event.content);
+            }
+        }
+    }
+
+    /// Resolve the NIP-05 identifier in a metadata event in the background,
+    /// the relays found are picked up by [`Self::record_nip05_relays`]. Each
+    /// identifier is resolved once, and only for the event's author.
+    fn resolve_nip05(&mut self, source: &Url, event: &Event) {
+        let nip05 = match Metadata::from_json(&event.content)
+            .ok()
+            .and_then(|m| m.nip05)
+        {
+            Some(nip05) => nip05,
+            None => return,
+        };
+        if !self.nip05_resolved.insert(nip05.clone()) {
+            return;
+        }
+        let source = source.clone();
+        let pubkey = event.pubkey;
+        let permits = self.nip05_permits.clone();
+        let sender = self.nip05_sender.clone();
+        let proxy = self.proxy;
+        let timeout = self.info_timeout;
+        tokio::spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            match tokio::time::timeout(timeout, get_profile(&nip05, proxy)).await {
+                Ok(Ok(profile)) if profile.public_key == pubkey => {
+                    let _ = sender.send((source, profile.relays));
+                }
+                Ok(Ok(_)) => debug!("NIP-05 {nip05} is not the author's"),
+                Ok(Err(e)) => debug!("Cannot resolve NIP-05 {nip05}: {e}"),
+                Err(_) => debug!("Cannot resolve NIP-05 {nip05}: timeout"),
             }
-            _ => {
-                debug!("Unsupported event {:?}", event.kind)
+        });
+    }
+
+    /// Add the relays found by NIP-05 resolutions finished so far
+    fn record_nip05_relays(&mut self) {
+        while let Ok((source, relays)) = self.nip05_receiver.try_recv() {
+            for relay in relays {
+                let _ = self.add_discovered_relay(&source, &relay);
             }
         }
     }
 
+    /// Record the subscription failures of the connection tasks so far
+    fn record_subscription_failures(&mut self) {
+        while let Ok((url, reason)) = self.subscription_failure_receiver.try_recv() {
+            self.stats.add_subscription_failure(&url, &reason);
+        }
+    }
+
+    /// Time left until the discovery idle stop, `None` without one
+    fn discovery_idle_left(&self) -> Option<Duration> {
+        let idle_for = self
+            .clock
+            .now()
+            .saturating_duration_since(self.time_last_discovery);
+        self.discovery_idle
+            .map(|idle| idle.saturating_sub(idle_for))
+    }
+
     fn update_event_time(&mut self) {
-        self.time_last_event = Self::now();
+        self.time_last_event = self.clock.now();
+    }
+
+    /// Time since the last event, on the monotonic clock so that wall
+    /// clock adjustments during the crawl do not trigger or delay the stop
+    fn get_last_event_ago(&self) -> Duration {
+        self.clock
+            .now()
+            .saturating_duration_since(self.time_last_event)
+    }
+
+    /// Enough of the connected and connecting relays, leaving out the
+    /// no-EOSE ones if excluded, signalled EOSE: `eose_count` of them
+    async fn eose_complete(&self, eose_count: usize) -> bool {
+        if self.tail || eose_count == 0 {
+            return false;
+        }
+        // nostr-sdk 0.19 has no relay status notifications, so the status is
+        // polled, only when an EOSE arrives or on the no-EOSE ticker
+        let relays = self.relay_client.relays().await;
+        let mut n_connected = 0;
+        let mut n_connecting = 0;
+        let mut n_no_eose = 0;
+        for (url, relay) in relays.iter() {
+            match relay.status().await {
+                RelayStatus::Connected => {
+                    n_connected += 1;
+                    if self.exclude_no_eose && self.is_no_eose(url) {
+                        n_no_eose += 1;
+                    }
+                }
+                RelayStatus::Connecting => n_connecting += 1,
+                _ => {}
+            }
+        }
+        debug!(
+            "EOSE from {eose_count} ({} relays, {n_connected} connected {n_connecting} connecting, {n_no_eose} left out without EOSE)",
+            relays.len()
+        );
+        let n_waited = n_connected + n_connecting - n_no_eose;
+        let complete =
+            n_waited > 0 && eose_count as f64 >= self.eose_completion_fraction * n_waited as f64;
+        if complete {
+            debug!("STOPPING; Enough relays signalled EOSE ({eose_count})");
+        }
+        complete
+    }
+
+    /// The relay served events, but no EOSE in the no-EOSE window since it
+    /// was subscribed to
+    fn is_no_eose(&self, url: &Url) -> bool {
+        let served = match self.relays.get(url) {
+            Some(meta) => !meta.eose && meta.event_count > 0,
+            None => false,
+        };
+        served
+            && self
+                .subscribed_at
+                .get(url)
+                .is_some_and(|at| at.elapsed() >= self.no_eose_window)
+    }
+
+    /// Check for stop: there was no event in the last few seconds, and
+    /// `eose_count` relays sent EOSE already
+    fn silence_reached(&self, eose_count: usize) -> bool {
+        !self.tail && eose_count >= 2 && self.get_last_event_ago() > SILENCE_TIMEOUT
+    }
+}
+
+impl Drop for RelayManager {
+    fn drop(&mut self) {
+        // Keep the relays found so far when the crawl panicked
+        if !self.dumped && std::thread::panicking() {
+            let _ = self.finish();
+        }
+    }
+}
+
+/// `text` matches the glob `pattern`, where `*` matches any (possibly
+/// empty) sequence of characters, ignoring ASCII case
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let text = text.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    // Without `*` there is a single part, which must match the whole text
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let last = match parts.split_last() {
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            last
+        }
+        None => return rest.is_empty(),
+    };
+    rest.ends_with(last)
+}
+
+/// Run `f`, returning the panic message if it panics, so that the work on
+/// one event or relay can't abort the whole crawl
+fn catch_panic<T>(f: impl FnOnce() -> T) -> std::result::Result<T, String> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).map_err(|payload| {
+        payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string())
+    })
+}
+
+/// Pass each event received on `receiver` to `handle`, until the sender is
+/// dropped, e.g. on the processor task. A panic is logged and counted per
+/// source relay instead of ending the task. Returns the handler state and
+/// the panic counts.
+async fn process_events<P>(
+    mut state: P,
+    mut receiver: mpsc::Receiver<(Url, Event)>,
+    handle: impl Fn(&mut P, &Event),
+) -> (P, HashMap<Url, u64>) {
+    let mut panics = HashMap::new();
+    while let Some((url, event)) = receiver.recv().await {
+        if let Err(e) = catch_panic(|| handle(&mut state, &event)) {
+            warn!("Processing event {} from {url} panicked: {e}", event.id);
+            *panics.entry(url).or_insert(0) += 1;
+        }
+    }
+    (state, panics)
+}
+
+/// The message says the relay is rate limiting us: the NIP-01
+/// `rate-limited:` prefix, or wording to that effect in a NOTICE
+fn is_rate_limited(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("rate-limited") || message.contains("rate limit")
+}
+
+/// Whether a relay notice, sent before its EOSE, means it rejected the
+/// subscription, e.g. "ERROR: bad req: filter too large"
+fn is_subscription_rejection(message: &str) -> bool {
+    let message = message.to_lowercase();
+    [
+        "bad req",
+        "filter",
+        "subscription",
+        "could not parse",
+        "invalid",
+    ]
+    .iter()
+    .any(|s| message.contains(s))
+}
+
+/// Delay suggested in a rate limiting message, the first number of seconds
+/// in it, e.g. "rate-limited: try again in 30s" or "retry after 30 seconds"
+fn suggested_delay(message: &str) -> Option<Duration> {
+    let words: Vec<&str> = message.split_whitespace().collect();
+    for (i, word) in words.iter().enumerate() {
+        let digits: String = word.chars().take_while(|c| c.is_ascii_digit()).collect();
+        if digits.is_empty() {
+            continue;
+        }
+        let unit = match &word[digits.len()..] {
+            "" => words.get(i + 1).copied().unwrap_or(""),
+            rest => rest,
+        };
+        let unit = unit.trim_end_matches(|c: char| !c.is_alphabetic());
+        if matches!(unit, "s" | "sec" | "secs" | "second" | "seconds") {
+            return digits.parse().ok().map(Duration::from_secs);
+        }
+    }
+    None
+}
+
+/// Current time as a Unix timestamp in milliseconds
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Relays listed in the content of a metadata event, in a `relays` field
+/// holding either a list of URLs or an object keyed by URL (as in contact
+/// lists). Not part of NIP-01, but some clients write it.
+fn metadata_relays(content: &str) -> Vec<String> {
+    let value: Value = match serde_json::from_str(content) {
+        Ok(value) => value,
+        Err(_) => return Vec::new(),
+    };
+    match &value["relays"] {
+        Value::Array(relays) => relays
+            .iter()
+            .filter_map(|r| r.as_str().map(String::from))
+            .collect(),
+        Value::Object(relays) => relays.keys().cloned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Receiver of the pool notifications, directly or through a larger buffer
+/// filled by a separate task
+enum Notifications {
+    Direct(broadcast::Receiver<RelayPoolNotification>),
+    /// Notifications, or the number of notifications missed
+    Buffered(mpsc::Receiver<std::result::Result<RelayPoolNotification, u64>>),
+}
+
+impl Notifications {
+    /// With a `buffer`, also returns the task filling it
+    fn new(
+        mut receiver: broadcast::Receiver<RelayPoolNotification>,
+        buffer: Option<usize>,
+    ) -> (Self, Option<tokio::task::JoinHandle<()>>) {
+        let size = match buffer {
+            Some(size) => size,
+            None => return (Notifications::Direct(receiver), None),
+        };
+        let (sender, buffered) = mpsc::channel(size);
+        let task = tokio::spawn(async move {
+            loop {
+                let item = match receiver.recv().await {
+                    Ok(notification) => Ok(notification),
+                    Err(RecvError::Lagged(missed)) => Err(missed),
+                    Err(RecvError::Closed) => break,
+                };
+                if sender.send(item).await.is_err() {
+                    break;
+                }
+            }
+        });
+        (Notifications::Buffered(buffered), Some(task))
+    }
+
+    /// The next notification, or the number of notifications missed since
+    /// the last one; `None` once the pool is gone
+    async fn recv(&mut self) -> Option<std::result::Result<RelayPoolNotification, u64>> {
+        match self {
+            Notifications::Direct(receiver) => match receiver.recv().await {
+                Ok(notification) => Some(Ok(notification)),
+                Err(RecvError::Lagged(missed)) => Some(Err(missed)),
+                Err(RecvError::Closed) => None,
+            },
+            Notifications::Buffered(receiver) => receiver.recv().await,
+        }
+    }
+}
+
+/// Periodically poll the status of each relay in the pool, and report the
+/// ones that have not been connected for at least `stuck_after`
+async fn poll_relay_health(
+    relay_client: Client,
+    interval: Duration,
+    stuck_after: Duration,
+    evicted: mpsc::Sender<Vec<Url>>,
+) {
+    let mut not_connected_since = HashMap::<Url, Instant>::new();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let mut dead = Vec::new();
+        for (url, relay) in relay_client.relays().await {
+            match relay.status().await {
+                RelayStatus::Connected => {
+                    not_connected_since.remove(&url);
+                    continue;
+                }
+                // Lost connections are evicted right away, only relays still
+                // connecting get `stuck_after` to make it
+                RelayStatus::Disconnected | RelayStatus::Terminated => {
+                    not_connected_since.remove(&url);
+                    dead.push(url);
+                    continue;
+                }
+                RelayStatus::Initialized | RelayStatus::Connecting => {}
+            }
+            let since = *not_connected_since
+                .entry(url.clone())
+                .or_insert_with(Instant::now);
+            if since.elapsed() >= stuck_after {
+                not_connected_since.remove(&url);
+                dead.push(url);
+            }
+        }
+        if !dead.is_empty() && evicted.send(dead).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Sleep until `deadline`, or forever if there is none
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for the next tick of an optional interval, forever if there is none
+async fn tick(ticker: &mut Option<tokio::time::Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Format a count with thousands separators, e.g. `15,204`
+fn format_count(count: u64) -> String {
+    let digits = count.to_string();
+    let mut res = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            res.push(',');
+        }
+        res.push(c);
+    }
+    res
+}
+
+/// Format a duration as e.g. `2m10s`, or `3h05m` from an hour on
+fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 60 * 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        catch_panic, glob_matches, is_rate_limited, is_subscription_rejection, process_events,
+        suggested_delay, Clock, DedupPolicy, RelayManager, StopReason, SILENCE_TIMEOUT,
+    };
+    use crate::processor::Processor;
+    use crate::relays::Relays;
+    use crate::reputation::ReputationStore;
+    use nostr_sdk::prelude::{Event, EventBuilder, EventId, Keys, Kind, Tag, Timestamp, Url};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::sync::mpsc;
+
+    fn event_id(i: u64) -> EventId {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&i.to_be_bytes());
+        EventId::from_slice(&bytes).unwrap()
+    }
+
+    struct FakeClock(Mutex<Instant>);
+
+    impl FakeClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn subscription_rejections() {
+        assert!(is_subscription_rejection(
+            "ERROR: bad req: filter too large"
+        ));
+        assert!(is_subscription_rejection("Too many subscriptions"));
+        assert!(is_subscription_rejection("invalid: kinds must be integers"));
+        assert!(!is_subscription_rejection("rate-limited: slow down"));
+        assert!(!is_subscription_rejection("Welcome to the relay!"));
+    }
+
+    #[tokio::test]
+    async fn discovery_idle_runs_on_the_clock() {
+        let clock = Arc::new(FakeClock(Mutex::new(Instant::now())));
+        let idle = Duration::from_secs(30);
+        let mut manager = RelayManager::new(Keys::generate(), Processor::new())
+            .with_clock(clock.clone())
+            .cancel_after_discovery_idle(idle);
+        assert_eq!(manager.discovery_idle_left(), Some(idle));
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(manager.discovery_idle_left(), Some(Duration::from_secs(10)));
+        let source = Url::parse("wss://source.example.com").unwrap();
+        assert!(manager.add_discovered_relay(&source, "wss://new.example.com"));
+        assert_eq!(manager.discovery_idle_left(), Some(idle));
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(manager.discovery_idle_left(), Some(Duration::ZERO));
+    }
+
+    fn event_at(keys: &Keys, kind: Kind, tags: &[Tag], created_at: u64) -> Event {
+        let mut event = EventBuilder::new(kind, "", tags).to_event(keys).unwrap();
+        event.created_at = Timestamp::from(created_at);
+        event
+    }
+
+    #[tokio::test]
+    async fn dedup_none_passes_every_event() {
+        let keys = Keys::generate();
+        let mut manager =
+            RelayManager::new(keys.clone(), Processor::new()).with_dedup_policy(DedupPolicy::None);
+        let event = event_at(&keys, Kind::TextNote, &[], 1000);
+        assert!(!manager.is_duplicate(&event, true));
+        assert!(!manager.is_duplicate(&event, false));
+    }
+
+    #[tokio::test]
+    async fn dedup_by_id_drops_repeats() {
+        let keys = Keys::generate();
+        let mut manager =
+            RelayManager::new(keys.clone(), Processor::new()).with_dedup_policy(DedupPolicy::ById);
+        let event = event_at(&keys, Kind::Metadata, &[], 1000);
+        assert!(!manager.is_duplicate(&event, true));
+        assert!(manager.is_duplicate(&event, false));
+        // Replaceable versions are told apart by id only
+        let older = event_at(&keys, Kind::Metadata, &[], 900);
+        assert!(!manager.is_duplicate(&older, true));
+    }
+
+    #[tokio::test]
+    async fn dedup_replaceable_keeps_newer_versions() {
+        let keys = Keys::generate();
+        let mut manager = RelayManager::new(keys.clone(), Processor::new())
+            .with_dedup_policy(DedupPolicy::Replaceable);
+        let first = event_at(&keys, Kind::Metadata, &[], 1000);
+        assert!(!manager.is_duplicate(&first, true));
+        assert!(manager.is_duplicate(&first, false));
+        let newer = event_at(&keys, Kind::Metadata, &[], 1100);
+        assert!(!manager.is_duplicate(&newer, true));
+        let older = event_at(&keys, Kind::Metadata, &[], 1050);
+        assert!(manager.is_duplicate(&older, true));
+        // Parameterized replaceable events are kept apart per d tag
+        let article = Kind::LongFormTextNote;
+        let a = [Tag::Identifier("a".to_string())];
+        let b = [Tag::Identifier("b".to_string())];
+        assert!(!manager.is_duplicate(&event_at(&keys, article, &a, 1000), true));
+        assert!(!manager.is_duplicate(&event_at(&keys, article, &b, 900), true));
+        assert!(manager.is_duplicate(&event_at(&keys, article, &a, 900), true));
+        // Regular events are never replaced
+        let note = event_at(&keys, Kind::TextNote, &[], 1000);
+        assert!(!manager.is_duplicate(&note, true));
+        let earlier_note = event_at(&keys, Kind::TextNote, &[], 900);
+        assert!(!manager.is_duplicate(&earlier_note, true));
     }
 
-    fn get_last_event_ago(&self) -> u64 {
-        Self::now() - self.time_last_event
+    #[tokio::test]
+    async fn event_rate_capped_per_window() {
+        let clock = Arc::new(FakeClock(Mutex::new(Instant::now())));
+        let mut manager = RelayManager::new(Keys::generate(), Processor::new())
+            .with_clock(clock.clone())
+            .with_max_event_rate(3);
+        let (flooding, quiet) = (
+            Url::parse("wss://flooding.example.com").unwrap(),
+            Url::parse("wss://quiet.example.com").unwrap(),
+        );
+        for _ in 0..3 {
+            assert!(!manager.over_event_rate(&flooding));
+        }
+        assert!(manager.over_event_rate(&flooding));
+        assert!(manager.over_event_rate(&flooding));
+        // Each relay has its own window
+        assert!(!manager.over_event_rate(&quiet));
+        clock.advance(Duration::from_millis(999));
+        assert!(manager.over_event_rate(&flooding));
+        clock.advance(Duration::from_millis(1));
+        assert!(!manager.over_event_rate(&flooding));
+    }
+
+    #[tokio::test]
+    async fn oversized_events_exceed_the_limit() {
+        let keys = Keys::generate();
+        let event = EventBuilder::new_text_note("some content", &[])
+            .to_event(&keys)
+            .unwrap();
+        let size = event.as_json().len();
+        let manager = RelayManager::new(keys.clone(), Processor::new());
+        assert!(!manager.is_oversized(&event));
+        let at_limit = RelayManager::new(keys.clone(), Processor::new()).with_max_event_size(size);
+        assert!(!at_limit.is_oversized(&event));
+        let above = RelayManager::new(keys.clone(), Processor::new()).with_max_event_size(size + 1);
+        assert!(!above.is_oversized(&event));
+        let below = RelayManager::new(keys, Processor::new()).with_max_event_size(size - 1);
+        assert!(below.is_oversized(&event));
+    }
+
+    #[tokio::test]
+    async fn processor_task_counts_panics() {
+        let keys = Keys::generate();
+        let relay = Url::parse("wss://relay.example.com").unwrap();
+        let (sender, receiver) = mpsc::channel(8);
+        let task = tokio::spawn(process_events(0, receiver, |handled: &mut u64, event| {
+            if event.content == "bad" {
+                panic!("malformed event");
+            }
+            *handled += 1;
+        }));
+        for content in ["good", "bad", "good", "bad"] {
+            let event = EventBuilder::new_text_note(content, &[])
+                .to_event(&keys)
+                .unwrap();
+            sender.send((relay.clone(), event)).await.unwrap();
+        }
+        drop(sender);
+        let (handled, panics) = task.await.unwrap();
+        assert_eq!(handled, 2);
+        assert_eq!(panics.get(&relay), Some(&2));
+    }
+
+    #[test]
+    fn catch_panic_returns_the_message() {
+        assert_eq!(catch_panic(|| 42), Ok(42));
+        assert_eq!(
+            catch_panic(|| panic!("bad tag")),
+            Err::<(), _>("bad tag".to_string())
+        );
+        let index = 3;
+        assert_eq!(
+            catch_panic(|| panic!("index {index} out of range")),
+            Err::<(), _>("index 3 out of range".to_string())
+        );
+    }
+
+    #[test]
+    fn glob_matches_hosts() {
+        assert!(glob_matches("*.de", "relay.nostr.de"));
+        assert!(glob_matches("*.DE", "relay.nostr.de"));
+        assert!(!glob_matches("*.de", "relay.nostr.dev"));
+        assert!(glob_matches("relay.example.com", "relay.example.com"));
+        assert!(!glob_matches("relay.example.com", "relay.example.com.evil"));
+        assert!(glob_matches("nostr.*.org", "nostr.relay.org"));
+        assert!(!glob_matches("nostr.*.org", "relay.nostr.org"));
+        assert!(glob_matches("*nostr*", "relay.nostr.org"));
+    }
+
+    #[tokio::test]
+    async fn silence_stop_fires_after_the_timeout() {
+        let clock = Arc::new(FakeClock(Mutex::new(Instant::now())));
+        let mut manager =
+            RelayManager::new(Keys::generate(), Processor::new()).with_clock(clock.clone());
+        clock.advance(SILENCE_TIMEOUT);
+        assert!(!manager.silence_reached(2));
+        clock.advance(Duration::from_secs(1));
+        assert!(manager.silence_reached(2));
+        assert!(!manager.silence_reached(1));
+        manager.update_event_time();
+        assert!(!manager.silence_reached(2));
+    }
+
+    #[tokio::test]
+    async fn lookback_widens_while_few_events() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let mut manager =
+            RelayManager::new(Keys::generate(), Processor::new()).with_adaptive_lookback(10, day);
+        let now = Timestamp::from(1_700_000_000);
+        assert!(!manager.widen_lookback(now).await);
+
+        manager.stop_reason = Some(StopReason::AllEose);
+        assert!(manager.widen_lookback(now).await);
+        assert_eq!(manager.lookback, day / 2);
+        assert_eq!(manager.stop_reason, None);
+        manager.stop_reason = Some(StopReason::Silence);
+        assert!(manager.widen_lookback(now).await);
+        assert_eq!(manager.lookback, day);
+        manager.stop_reason = Some(StopReason::AllEose);
+        assert!(!manager.widen_lookback(now).await);
+        let filters = manager.subscription_filters(now);
+        assert_eq!(filters[0].since, Some(now - day));
+
+        let mut manager =
+            RelayManager::new(Keys::generate(), Processor::new()).with_adaptive_lookback(10, day);
+        manager.stats.event_ids.extend((0..10).map(event_id));
+        manager.stop_reason = Some(StopReason::AllEose);
+        assert!(!manager.widen_lookback(now).await);
+    }
+
+    #[tokio::test]
+    async fn lookback_widening_keeps_the_full_subscription() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let mut manager = RelayManager::new(Keys::generate(), Processor::new())
+            .with_max_filter_kinds(1)
+            .with_kind_lookback(Kind::LongFormTextNote, 3 * day)
+            .with_adaptive_lookback(10, day);
+        let now = Timestamp::from(1_700_000_000);
+        manager.stop_reason = Some(StopReason::AllEose);
+        assert!(manager.widen_lookback(now).await);
+
+        let windows: Vec<(Vec<Kind>, Option<Timestamp>, Option<Timestamp>)> = manager
+            .subscription_filters(now)
+            .into_iter()
+            .map(|f| (f.kinds.unwrap_or_default(), f.since, f.until))
+            .collect();
+        let widened = Some(now - day / 2);
+        assert_eq!(
+            windows,
+            vec![
+                (vec![Kind::ContactList], widened, Some(now)),
+                (vec![Kind::RecommendRelay], widened, Some(now)),
+                (vec![Kind::LongFormTextNote], Some(now - 3 * day), Some(now)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscription_filters_follow_kind_lookbacks() {
+        let hour = Duration::from_secs(60 * 60);
+        let manager = RelayManager::new(Keys::generate(), Processor::new())
+            .with_kind_lookback(Kind::ContactList, 12 * hour)
+            .with_kind_lookback(Kind::LongFormTextNote, 24 * hour)
+            .with_kind_lookback(Kind::RelayList, 72 * hour);
+        let now = Timestamp::from(1_700_000_000);
+
+        let windows: Vec<(Vec<Kind>, Option<Timestamp>)> = manager
+            .subscription_filters(now)
+            .into_iter()
+            .map(|f| (f.kinds.unwrap_or_default(), f.since))
+            .collect();
+        assert_eq!(
+            windows,
+            vec![
+                (vec![Kind::RecommendRelay], Some(now - 6 * hour)),
+                (vec![Kind::ContactList], Some(now - 12 * hour)),
+                (vec![Kind::LongFormTextNote], Some(now - 24 * hour)),
+                (vec![Kind::RelayList], Some(now - 72 * hour)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscription_filters_chunk_kinds() {
+        let now = Timestamp::from(1_700_000_000);
+        let chunks = |manager: RelayManager| -> Vec<Vec<Kind>> {
+            manager
+                .subscription_filters(now)
+                .into_iter()
+                .map(|f| f.kinds.unwrap_or_default())
+                .collect()
+        };
+        // Relay kinds and metadata, four kinds in one window
+        let crawl = || {
+            RelayManager::new(Keys::generate(), Processor::new())
+                .relays_only(true)
+                .with_metadata_discovery(true)
+        };
+        assert_eq!(
+            chunks(crawl()),
+            vec![vec![
+                Kind::ContactList,
+                Kind::RecommendRelay,
+                Kind::RelayList,
+                Kind::Metadata
+            ]]
+        );
+        assert_eq!(
+            chunks(crawl().with_max_filter_kinds(2)),
+            vec![
+                vec![Kind::ContactList, Kind::RecommendRelay],
+                vec![Kind::RelayList, Kind::Metadata],
+            ]
+        );
+        assert_eq!(
+            chunks(crawl().with_max_filter_kinds(3)),
+            vec![
+                vec![Kind::ContactList, Kind::RecommendRelay, Kind::RelayList],
+                vec![Kind::Metadata],
+            ]
+        );
+        // Windows are chunked apart
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(
+            chunks(
+                crawl()
+                    .with_kind_lookback(Kind::RelayList, day)
+                    .with_max_filter_kinds(2)
+            ),
+            vec![
+                vec![Kind::ContactList, Kind::RecommendRelay],
+                vec![Kind::Metadata],
+                vec![Kind::RelayList],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn discovery_stops_at_the_relay_cap() {
+        let mut manager = RelayManager::new(Keys::generate(), Processor::new()).with_max_relays(2);
+        let source = Url::parse("wss://source.example.com").unwrap();
+        assert!(!manager.relay_cap_reached());
+        assert!(manager.add_discovered_relay(&source, "wss://a.example.com"));
+        assert!(manager.add_discovered_relay(&source, "wss://b.example.com"));
+        assert!(manager.relay_cap_reached());
+        assert!(!manager.add_discovered_relay(&source, "wss://c.example.com"));
+        assert!(!manager.add_discovered_relay(&source, "wss://a.example.com"));
+        assert_eq!(manager.relays.count(), 2);
+        assert_eq!(manager.stats.count_new_relay_hints, 2);
+        assert_eq!(manager.stats.count_capped_relay_hints, 2);
+    }
+
+    #[tokio::test]
+    async fn allowlist_applies_to_warm_start_and_reputation() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let (inside, outside) = (url("wss://in.example.com"), url("wss://out.example.com"));
+        let allowlist: HashSet<Url> = [inside.clone()].into_iter().collect();
+
+        let mut relays = Relays::new();
+        relays.add(inside.as_str());
+        relays.add(outside.as_str());
+        relays.add(outside.as_str());
+        let manager = RelayManager::new(Keys::generate(), Processor::new())
+            .with_warm_start(&relays.to_dump())
+            .with_allowlist(allowlist.clone());
+        assert_eq!(manager.warm_start, vec![inside.clone()]);
+        assert!(manager.dialable(&inside));
+        assert!(!manager.dialable(&outside));
+
+        let mut store = ReputationStore::default();
+        let now = 1_700_000_000;
+        for u in [&inside, &outside] {
+            relays.set_connect_latency(u, Duration::from_millis(100));
+            store.record_run(u, relays.get(u).unwrap(), now, Duration::from_secs(3600));
+        }
+        let manager = RelayManager::new(Keys::generate(), Processor::new())
+            .with_reputation(store)
+            .with_allowlist(allowlist);
+        assert_eq!(manager.warm_start, vec![inside.clone()]);
+        assert!(!manager.dialable(&outside));
     }
 
-    fn now() -> u64 {
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
+    #[test]
+    fn suggested_delay_of_rate_limiting_messages() {
+        assert!(is_rate_limited("rate-limited: slow down there chief"));
+        assert!(is_rate_limited(
+            "Rate limit exceeded, retry after 30 seconds."
+        ));
+        assert!(!is_rate_limited("blocked: you are banned"));
+        assert_eq!(
+            suggested_delay("rate-limited: try again in 30s"),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            suggested_delay("Rate limit exceeded, retry after 30 seconds."),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(suggested_delay("rate-limited: max 10 events"), None);
     }
 }
@@ -1,11 +1,483 @@
-use log::info;
+use crate::relay_info::{RelayInfo, SkipReason};
 use log::trace;
-use nostr_sdk::prelude::Url;
-use std::collections::HashSet;
+use nostr_sdk::prelude::{RelayInformationDocument, Tag, Timestamp, Url};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Render a relay URL for output, e.g. `wss://relay.example`: without the
+/// default port of the scheme, and without the path when it is empty
+pub fn canonical_url(url: &Url) -> String {
+    let mut res = format!("{}://{}", url.scheme(), url.host_str().unwrap_or(""));
+    if let Some(port) = url.port() {
+        if Some(port) != default_port(url.scheme()) {
+            res.push_str(&format!(":{port}"));
+        }
+    }
+    if url.path() != "/" {
+        res.push_str(url.path());
+    }
+    if let Some(query) = url.query() {
+        res.push('?');
+        res.push_str(query);
+    }
+    res
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "ws" | "http" => Some(80),
+        "wss" | "https" => Some(443),
+        _ => None,
+    }
+}
+
+/// Domain suffixes reserved for local or invalid names, never a public relay
+const RESERVED_SUFFIXES: &[&str] = &[".localhost", ".local", ".invalid", ".test"];
+
+/// Whether `url` is an obvious placeholder rather than a relay reachable
+/// from the internet, as often found in relay hints: no host, a single
+/// label host (`wss://relay`, `ws://localhost`), a reserved name or a
+/// loopback, private or unspecified address
+pub fn is_placeholder(url: &Url) -> bool {
+    let host = match url.host_str() {
+        Some(host) if !host.is_empty() => host.trim_end_matches('.').to_lowercase(),
+        _ => return true,
+    };
+    if let Ok(ip) = host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+    {
+        return match ip {
+            IpAddr::V4(ip) => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_private()
+                    || ip.is_link_local()
+                    || ip.is_broadcast()
+            }
+            IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified(),
+        };
+    }
+    !host.contains('.') || RESERVED_SUFFIXES.iter().any(|s| host.ends_with(s))
+}
+
+/// Version of the relay dump format, bumped whenever the format changes:
+///
+/// - 1: `url` per relay
+/// - 2: `nip11` and `nip11_skipped` per relay
+/// - 3: `ref_count`, `first_seen` and `last_seen` per relay
+/// - 4: `created_at_hours`
+/// - 5: `url` in canonical form, see [`canonical_url`]
+/// - 6: `paid` per relay
+/// - 7: `connected` and `eose`
+/// - 8: `addr` per relay
+/// - 9: `nip11_raw` per relay
+pub const DUMP_FORMAT_VERSION: u32 = 9;
+
+/// Relay dump, as printed at the end of a crawl:
+///
+/// ```json
+/// {
+///   "format_version": 9,
+///   "generated_at": 1700000000,
+///   "relays": [
+///     {
+///       "url": "wss://relay.damus.io",
+///       "ref_count": 12,
+///       "first_seen": 1699999000,
+///       "last_seen": 1699999900,
+///       "nip11": { "name": "damus.io" },
+///       "paid": false,
+///       "addr": "192.0.2.1:443",
+///       "nip11_raw": "{\"name\":\"damus.io\"}"
+///     },
+///     { "url": "wss://nos.lol", "ref_count": 1, ..., "nip11_skipped": "timeout" }
+///   ],
+///   "connected": ["wss://relay.damus.io"],
+///   "eose": ["wss://relay.damus.io"],
+///   "created_at_hours": { "1699992000": 310, "1699995600": 1204 }
+/// }
+/// ```
+///
+/// `generated_at` is a Unix timestamp in seconds, `relays` holds one entry
+/// per discovered relay, sorted by URL. `ref_count` is the number of times
+/// the relay was referenced, `first_seen`/`last_seen` the Unix timestamps
+/// of the first and last reference. `nip11` is the relay information
+/// document, `nip11_skipped` why it could not be fetched; both are absent
+/// when relay information was not requested. `nip11_raw` is the document as
+/// served, only when requested. `paid` tells if the NIP-11 document requires
+/// payment or lists fees, absent without a document. `addr` is the socket
+/// address the relay host resolved to, when resolving was requested.
+/// `connected` lists the relays connected to, sorted by URL, and `eose` the
+/// ones of them that signalled EOSE. `created_at_hours` counts the received
+/// events per hour of `created_at`, keyed by the start of the hour (Unix
+/// timestamp, as a string).
+#[derive(Serialize, Deserialize)]
+pub struct RelaysDump {
+    pub format_version: u32,
+    pub generated_at: u64,
+    pub relays: Vec<RelayEntry>,
+    #[serde(default)]
+    pub connected: Vec<String>,
+    #[serde(default)]
+    pub eose: Vec<String>,
+    #[serde(default)]
+    pub created_at_hours: BTreeMap<u64, u64>,
+}
+
+impl RelaysDump {
+    /// Print the dump as a JSON document
+    pub fn print_json(&self) -> serde_json::Result<()> {
+        println!("{}", serde_json::to_string(self)?);
+        Ok(())
+    }
+
+    /// Parse a dump printed by a previous run
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    /// Read a dump saved from a previous run
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(Self::from_json(&json)?)
+    }
+
+    /// Save the dump in `dir` (created if needed) as
+    /// `relays-<generated_at>.json`, then delete the oldest such dumps
+    /// beyond the `keep` newest; the one saved is always kept. Other files
+    /// are left alone. Returns the path of the dump saved.
+    pub fn save_rotated(&self, dir: &Path, keep: usize) -> io::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{ROTATED_DUMP_PREFIX}{}.json", self.generated_at));
+        std::fs::write(&path, serde_json::to_string(self)?)?;
+        let mut saved: Vec<(u64, PathBuf)> = std::fs::read_dir(dir)?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let generated_at = entry
+                    .file_name()
+                    .to_str()?
+                    .strip_prefix(ROTATED_DUMP_PREFIX)?
+                    .strip_suffix(".json")?
+                    .parse()
+                    .ok()?;
+                Some((generated_at, entry.path()))
+            })
+            .collect();
+        saved.sort();
+        let excess = saved.len().saturating_sub(keep.max(1));
+        for (_, old) in saved.drain(..excess) {
+            std::fs::remove_file(old)?;
+        }
+        Ok(path)
+    }
+
+    /// What changed from this dump to the `newer` one. A relay is stale
+    /// when it was not referenced within `stale_after` of the time its dump
+    /// was generated.
+    pub fn diff(&self, newer: &RelaysDump, stale_after: Duration) -> DumpDiff {
+        let old: BTreeMap<&str, &RelayEntry> =
+            self.relays.iter().map(|e| (e.url.as_str(), e)).collect();
+        let new: BTreeMap<&str, &RelayEntry> =
+            newer.relays.iter().map(|e| (e.url.as_str(), e)).collect();
+        let is_stale = |e: &RelayEntry, generated_at: u64| {
+            e.last_seen < generated_at.saturating_sub(stale_after.as_secs())
+        };
+        let mut res = DumpDiff::default();
+        for (url, e) in &new {
+            match old.get(url) {
+                None => res.added.push(url.to_string()),
+                Some(o) => {
+                    res.unchanged.push(url.to_string());
+                    if o.ref_count != e.ref_count {
+                        res.ref_count_changes.push(RefCountChange {
+                            url: url.to_string(),
+                            old: o.ref_count,
+                            new: e.ref_count,
+                        });
+                    }
+                    if is_stale(e, newer.generated_at) && !is_stale(o, self.generated_at) {
+                        res.newly_stale.push(url.to_string());
+                    }
+                }
+            }
+        }
+        res.removed = old
+            .keys()
+            .filter(|url| !new.contains_key(*url))
+            .map(|url| url.to_string())
+            .collect();
+        res
+    }
+
+    /// The relays ranked by score, best first. The score is the number of
+    /// references, ties are broken by URL.
+    pub fn top_relays(&self, n: usize) -> Vec<&RelayEntry> {
+        let mut ranked: Vec<&RelayEntry> = self.relays.iter().collect();
+        ranked.sort_by(|a, b| b.ref_count.cmp(&a.ref_count).then(a.url.cmp(&b.url)));
+        ranked.truncate(n);
+        ranked
+    }
+}
+
+/// One relay in a [`RelaysDump`]
+#[derive(Serialize, Deserialize)]
+pub struct RelayEntry {
+    pub url: String,
+    pub ref_count: u64,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nip11: Option<RelayInformationDocument>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nip11_skipped: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paid: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addr: Option<String>,
+    /// The NIP-11 document as served, see [`Relays::set_keep_raw_info`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nip11_raw: Option<String>,
+}
+
+/// File name prefix of the dumps saved by [`RelaysDump::save_rotated`],
+/// followed by the generation time and `.json`
+const ROTATED_DUMP_PREFIX: &str = "relays-";
+
+/// Difference between two relay dumps, see [`RelaysDump::diff`]. The
+/// relays are sorted by URL.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DumpDiff {
+    /// Relays only in the newer dump
+    pub added: Vec<String>,
+    /// Relays only in the older dump
+    pub removed: Vec<String>,
+    /// Relays in both dumps
+    pub unchanged: Vec<String>,
+    /// Relays in both dumps whose number of references changed
+    pub ref_count_changes: Vec<RefCountChange>,
+    /// Relays in both dumps that are stale in the newer one only
+    pub newly_stale: Vec<String>,
+}
+
+impl DumpDiff {
+    /// Print the difference for humans, one relay per line
+    pub fn print(&self) {
+        let print_list = |title: &str, urls: &[String]| {
+            println!("{} ({}):", title, urls.len());
+            for url in urls {
+                println!("  {url}");
+            }
+        };
+        print_list("Added", &self.added);
+        print_list("Removed", &self.removed);
+        println!("Unchanged: {}", self.unchanged.len());
+        println!(
+            "Reference count changes ({}):",
+            self.ref_count_changes.len()
+        );
+        for c in &self.ref_count_changes {
+            println!(
+                "  {}: {} -> {} ({:+})",
+                c.url,
+                c.old,
+                c.new,
+                c.new as i64 - c.old as i64
+            );
+        }
+        print_list("Newly stale", &self.newly_stale);
+    }
+}
+
+/// Change of the number of references to a relay between two dumps
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RefCountChange {
+    pub url: String,
+    pub old: u64,
+    pub new: u64,
+}
+
+/// Headline numbers of the relays, see [`Relays::stats_summary`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RelaysSummary {
+    pub total: usize,
+    pub distinct_hosts: usize,
+    pub wss_count: usize,
+    pub ws_count: usize,
+    /// Relays on Tor onion services, whatever the scheme
+    pub onion_count: usize,
+}
+
+/// What is known about a relay
+#[derive(Debug, Clone)]
+pub struct RelayMeta {
+    /// Number of times the relay was referenced
+    pub ref_count: u64,
+    /// Time of the first reference (Unix timestamp)
+    pub first_seen: u64,
+    /// Time of the last reference (Unix timestamp)
+    pub last_seen: u64,
+    /// NIP-11 information document, or why it could not be fetched
+    pub info: Option<Result<RelayInformationDocument, SkipReason>>,
+    /// The NIP-11 document as served, if kept
+    pub raw_info: Option<String>,
+    /// The relay requires payment or charges fees, per its NIP-11 document
+    pub paid: Option<bool>,
+    /// Time taken by the last successful connection
+    pub connect_latency: Option<Duration>,
+    /// The relay signalled EOSE for the crawl subscription
+    pub eose: bool,
+    /// Address the relay host resolved to
+    pub addr: Option<SocketAddr>,
+    /// Listed in a NIP-65 relay list for reading, explicitly or unmarked
+    pub listed_read: bool,
+    /// Listed in a NIP-65 relay list for writing, explicitly or unmarked
+    pub listed_write: bool,
+    /// Number of events received from the relay
+    pub event_count: u64,
+    /// `created_at` of the newest event received from the relay
+    pub newest_event: Option<u64>,
+    /// Relays that served events referencing this relay
+    pub referenced_by: BTreeSet<Url>,
+}
+
+impl RelayMeta {
+    /// The relay advertises support for `nip` in its NIP-11 document
+    pub fn supports_nip(&self, nip: u16) -> bool {
+        match &self.info {
+            Some(Ok(info)) => info
+                .supported_nips
+                .as_ref()
+                .map(|nips| nips.contains(&nip))
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    fn new(now: u64) -> Self {
+        Self {
+            ref_count: 0,
+            first_seen: now,
+            last_seen: now,
+            info: None,
+            raw_info: None,
+            paid: None,
+            connect_latency: None,
+            eose: false,
+            addr: None,
+            listed_read: false,
+            listed_write: false,
+            event_count: 0,
+            newest_event: None,
+            referenced_by: BTreeSet::new(),
+        }
+    }
+
+    /// Merge what `other` knows of the same relay: references and events
+    /// add up, the first and last references extend, and the rest is kept
+    /// unless only `other` knows it
+    fn merge(&mut self, other: &RelayMeta) {
+        self.ref_count += other.ref_count;
+        self.first_seen = self.first_seen.min(other.first_seen);
+        self.last_seen = self.last_seen.max(other.last_seen);
+        if self.info.is_none() || matches!((&self.info, &other.info), (Some(Err(_)), Some(Ok(_)))) {
+            self.info = other.info.clone();
+            self.raw_info = other.raw_info.clone();
+        }
+        self.paid = self.paid.or(other.paid);
+        self.connect_latency = match (self.connect_latency, other.connect_latency) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.eose |= other.eose;
+        self.addr = self.addr.or(other.addr);
+        self.listed_read |= other.listed_read;
+        self.listed_write |= other.listed_write;
+        self.event_count += other.event_count;
+        self.newest_event = self.newest_event.max(other.newest_event);
+        self.referenced_by
+            .extend(other.referenced_by.iter().cloned());
+    }
+}
+
+/// Which relay URLs are taken to be the same relay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DedupStrictness {
+    /// Only URLs differing in a trailing slash are merged:
+    /// `wss://host/free/` and `wss://host/free` are one relay, but
+    /// `wss://host/free` and `wss://host/paid` (or another port) are not
+    #[default]
+    Loose,
+    /// All URLs with the same scheme and host are merged, whatever their port,
+    /// path or query; the relay is kept as `scheme://host/`
+    HostOnly,
+}
+
+impl DedupStrictness {
+    /// The URL a relay is known by
+    pub fn key(&self, url: &Url) -> Url {
+        let mut key = url.clone();
+        match self {
+            DedupStrictness::Loose => {
+                let path = url.path().trim_end_matches('/').to_string();
+                key.set_path(&path);
+            }
+            DedupStrictness::HostOnly => {
+                let _ = key.set_port(None);
+                key.set_path("");
+                key.set_query(None);
+                key.set_fragment(None);
+            }
+        }
+        key
+    }
+}
+
+/// Whether a relay entered or left the set, see [`RelayChange`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+}
+
+/// One entry of the change log of [`Relays`], see
+/// [`Relays::enable_change_log`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RelayChange {
+    /// Unix timestamp
+    pub timestamp: u64,
+    pub relay: String,
+    pub change: ChangeKind,
+    /// Why, e.g. `bootstrap` or `referenced by wss://relay.example`
+    pub reason: String,
+}
 
 /// Maintain a list of all encountered relays
 pub struct Relays {
-    r: HashSet<Url>,
+    r: HashMap<Url, RelayMeta>,
+    /// Which URLs are merged into one relay
+    dedup: DedupStrictness,
+    /// If set, only these relays are accepted
+    allowlist: Option<HashSet<Url>>,
+    /// Number of references to relays rejected by the allowlist
+    outside_allowlist: HashMap<Url, u64>,
+    /// Every addition and removal, if enabled
+    change_log: Option<Vec<RelayChange>>,
+    /// Accept placeholder URLs (see [`is_placeholder`]), e.g. to crawl a
+    /// local test relay
+    allow_placeholders: bool,
+    /// Number of references to placeholder URLs rejected
+    placeholders: u64,
+    /// Keep the NIP-11 documents as served
+    keep_raw_info: bool,
 }
 
 impl Default for Relays {
@@ -17,14 +489,114 @@ impl Default for Relays {
 impl Relays {
     pub fn new() -> Self {
         Self {
-            r: HashSet::default(),
+            r: HashMap::default(),
+            dedup: DedupStrictness::default(),
+            allowlist: None,
+            outside_allowlist: HashMap::default(),
+            change_log: None,
+            allow_placeholders: false,
+            placeholders: 0,
+            keep_raw_info: false,
         }
     }
 
+    /// Record every relay added or removed from now on, with the time and
+    /// the reason, see [`Relays::change_log`]. Off by default, the log grows
+    /// with every relay.
+    pub fn enable_change_log(&mut self) {
+        self.change_log.get_or_insert_with(Vec::new);
+    }
+
+    /// The relays added and removed, in order, empty unless enabled
+    pub fn change_log(&self) -> &[RelayChange] {
+        self.change_log.as_deref().unwrap_or_default()
+    }
+
+    fn record_change(&mut self, url: &Url, change: ChangeKind, reason: &dyn fmt::Display) {
+        if let Some(log) = &mut self.change_log {
+            log.push(RelayChange {
+                timestamp: Timestamp::now().as_u64(),
+                relay: canonical_url(url),
+                change,
+                reason: reason.to_string(),
+            });
+        }
+    }
+
+    /// Set which URLs are merged into one relay, before adding any
+    pub fn set_dedup_strictness(&mut self, dedup: DedupStrictness) {
+        self.dedup = dedup;
+    }
+
+    fn meta_mut(&mut self, url: &Url) -> Option<&mut RelayMeta> {
+        let key = self.dedup.key(url);
+        self.r.get_mut(&key)
+    }
+
+    /// Accept only the given relays from now on, and add them. References
+    /// to other relays are still counted (see [`Relays::outside_allowlist`]),
+    /// but they are not added.
+    pub fn set_allowlist(&mut self, allowlist: HashSet<Url>) {
+        let allowlist: HashSet<Url> = allowlist.iter().map(|u| self.dedup.key(u)).collect();
+        for u in &allowlist {
+            self.add_with_reason(u.as_str(), "allowlist");
+        }
+        self.retain("outside allowlist", |u, _| allowlist.contains(u));
+        self.allowlist = Some(allowlist);
+    }
+
+    pub fn is_allowed(&self, url: &Url) -> bool {
+        self.allowlist
+            .as_ref()
+            .map(|a| a.contains(&self.dedup.key(url)))
+            .unwrap_or(true)
+    }
+
+    /// Relays referenced but rejected by the allowlist, with their number of
+    /// references
+    pub fn outside_allowlist(&self) -> &HashMap<Url, u64> {
+        &self.outside_allowlist
+    }
+
+    /// Accept placeholder URLs from now on, rejected by default
+    pub fn set_allow_placeholders(&mut self, allow: bool) {
+        self.allow_placeholders = allow;
+    }
+
+    /// Number of references to placeholder URLs rejected
+    pub fn placeholders(&self) -> u64 {
+        self.placeholders
+    }
+
+    /// Record a reference to a relay, returns true if it wasn't known yet.
+    /// Placeholder URLs (unless allowed) and relays not in the allowlist (if
+    /// any) are rejected.
     pub fn add(&mut self, s1: &str) -> bool {
+        self.add_with_reason(s1, "referenced")
+    }
+
+    /// As [`Relays::add`], with the reason recorded in the change log if
+    /// the relay is new
+    pub fn add_with_reason(&mut self, s1: &str, reason: impl fmt::Display) -> bool {
         let mut res = false;
         if let Ok(u) = Url::parse(s1) {
-            res = self.r.insert(u);
+            if !self.allow_placeholders && is_placeholder(&u) {
+                self.placeholders += 1;
+                return false;
+            }
+            let u = self.dedup.key(&u);
+            if !self.is_allowed(&u) {
+                *self.outside_allowlist.entry(u).or_insert(0) += 1;
+                return false;
+            }
+            let now = Timestamp::now().as_u64();
+            res = !self.r.contains_key(&u);
+            if res {
+                self.record_change(&u, ChangeKind::Added, &reason);
+            }
+            let meta = self.r.entry(u).or_insert_with(|| RelayMeta::new(now));
+            meta.ref_count += 1;
+            meta.last_seen = now;
             if res {
                 self.print();
             }
@@ -32,13 +604,287 @@ impl Relays {
         res
     }
 
+    /// Add bootstrap relays, in order, until `max` relays are known.
+    /// Relays already known, from a seed or earlier in the list, are
+    /// skipped and not counted as references. Returns the number added.
+    pub fn add_bootstrap(&mut self, urls: &[&str], max: usize) -> usize {
+        let mut added = 0;
+        for s in urls {
+            if self.count() >= max {
+                break;
+            }
+            let known = Url::parse(s)
+                .map(|u| self.r.contains_key(&self.dedup.key(&u)))
+                .unwrap_or(true);
+            if !known && self.add_with_reason(s, "bootstrap") {
+                added += 1;
+            }
+        }
+        added
+    }
+
+    pub fn get(&self, url: &Url) -> Option<&RelayMeta> {
+        self.r.get(&self.dedup.key(url))
+    }
+
+    /// All relays with their metadata, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&Url, &RelayMeta)> {
+        self.r.iter()
+    }
+
+    /// Keep only the relays for which `f` returns true, returns the number
+    /// of relays removed. The removals are logged with `reason`.
+    pub fn retain<F: FnMut(&Url, &RelayMeta) -> bool>(&mut self, reason: &str, mut f: F) -> usize {
+        let before = self.r.len();
+        let mut removed = Vec::new();
+        self.r.retain(|u, m| {
+            let keep = f(u, m);
+            if !keep {
+                removed.push(u.clone());
+            }
+            keep
+        });
+        for u in &removed {
+            self.record_change(u, ChangeKind::Removed, &reason);
+        }
+        before - self.r.len()
+    }
+
+    /// Create with room for `capacity` relays, e.g. when seeding from a large
+    /// relay list
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            r: HashMap::with_capacity(capacity),
+            ..Self::new()
+        }
+    }
+
+    /// The relays known to `self` or `other`, e.g. to combine crawls from
+    /// several vantage points. Relays known to both have their metadata
+    /// merged. The result merges URLs as `self` does, and accepts any relay.
+    pub fn union(&self, other: &Relays) -> Relays {
+        let mut r = self.r.clone();
+        for (u, m) in self.rekeyed(other) {
+            r.entry(u).and_modify(|s| s.merge(&m)).or_insert(m);
+        }
+        self.with_relays(r)
+    }
+
+    /// The relays known to both `self` and `other`, with their metadata
+    /// merged, e.g. the relays reachable from every vantage point
+    pub fn intersection(&self, other: &Relays) -> Relays {
+        let r = self
+            .rekeyed(other)
+            .into_iter()
+            .filter_map(|(u, m)| {
+                let mut meta = self.r.get(&u)?.clone();
+                meta.merge(&m);
+                Some((u, meta))
+            })
+            .collect();
+        self.with_relays(r)
+    }
+
+    /// The relays known to `self` but not to `other`
+    pub fn difference(&self, other: &Relays) -> Relays {
+        let other = self.rekeyed(other);
+        let r = self
+            .r
+            .iter()
+            .filter(|(u, _)| !other.contains_key(*u))
+            .map(|(u, m)| (u.clone(), m.clone()))
+            .collect();
+        self.with_relays(r)
+    }
+
+    /// The relays of `other`, keyed as `self` merges URLs
+    fn rekeyed(&self, other: &Relays) -> HashMap<Url, RelayMeta> {
+        let mut res: HashMap<Url, RelayMeta> = HashMap::with_capacity(other.r.len());
+        for (u, m) in &other.r {
+            res.entry(self.dedup.key(u))
+                .and_modify(|s| s.merge(m))
+                .or_insert_with(|| m.clone());
+        }
+        res
+    }
+
+    fn with_relays(&self, r: HashMap<Url, RelayMeta>) -> Relays {
+        Relays {
+            r,
+            dedup: self.dedup,
+            ..Relays::new()
+        }
+    }
+
+    /// Release excess memory, once no more relays will be added
+    pub fn shrink_to_fit(&mut self) {
+        self.r.shrink_to_fit();
+    }
+
+    /// Keep the NIP-11 documents as served, to include them verbatim in the
+    /// dump. Off by default, the parsed documents are usually enough.
+    pub fn set_keep_raw_info(&mut self, keep: bool) {
+        self.keep_raw_info = keep;
+    }
+
+    pub fn set_info(&mut self, url: &Url, info: Result<RelayInfo, SkipReason>) {
+        let keep_raw_info = self.keep_raw_info;
+        if let Some(meta) = self.meta_mut(url) {
+            meta.paid = info.as_ref().ok().map(|i| i.payment_required);
+            meta.raw_info = info
+                .as_ref()
+                .ok()
+                .filter(|i| keep_raw_info && !i.raw.is_empty())
+                .map(|i| i.raw.clone());
+            meta.info = Some(info.map(|i| i.document));
+        }
+    }
+
+    pub fn set_connect_latency(&mut self, url: &Url, latency: Duration) {
+        if let Some(meta) = self.meta_mut(url) {
+            meta.connect_latency = Some(latency);
+        }
+    }
+
+    /// Record that a NIP-65 relay list lists the relay with `marker`
+    /// (`read`, `write`, or none for both)
+    pub fn add_listed_role(&mut self, url: &Url, marker: Option<&str>) {
+        if let Some(meta) = self.meta_mut(url) {
+            match marker {
+                Some("read") => meta.listed_read = true,
+                Some("write") => meta.listed_write = true,
+                _ => {
+                    meta.listed_read = true;
+                    meta.listed_write = true;
+                }
+            }
+        }
+    }
+
+    /// One NIP-65 `r` tag per relay, sorted, e.g. for a relay list event.
+    /// The tag is marked `read` or `write` if the relay was only listed in
+    /// that role by the relay lists seen, see [`Relays::add_listed_role`].
+    pub fn to_relay_list_tags(&self) -> Vec<Tag> {
+        self.as_vec_sorted()
+            .into_iter()
+            .map(|u| {
+                let m = &self.r[&u];
+                let marker = match (m.listed_read, m.listed_write) {
+                    (true, false) => Some("read".to_string()),
+                    (false, true) => Some("write".to_string()),
+                    _ => None,
+                };
+                Tag::RelayMetadata(canonical_url(&u), marker)
+            })
+            .collect()
+    }
+
+    pub fn set_addr(&mut self, url: &Url, addr: SocketAddr) {
+        if let Some(meta) = self.meta_mut(url) {
+            meta.addr = Some(addr);
+        }
+    }
+
+    /// Groups of relays whose hosts resolved to the same address, i.e.
+    /// likely served by the same server, sorted by address; each group
+    /// sorted, with at least two relays
+    pub fn addr_clusters(&self) -> Vec<(SocketAddr, Vec<Url>)> {
+        let mut groups = BTreeMap::<SocketAddr, Vec<Url>>::new();
+        for (url, meta) in &self.r {
+            if let Some(addr) = meta.addr {
+                groups.entry(addr).or_default().push(url.clone());
+            }
+        }
+        groups
+            .into_iter()
+            .filter(|(_, urls)| urls.len() > 1)
+            .map(|(addr, mut urls)| {
+                urls.sort();
+                (addr, urls)
+            })
+            .collect()
+    }
+
+    pub fn set_eose(&mut self, url: &Url) {
+        if let Some(meta) = self.meta_mut(url) {
+            meta.eose = true;
+        }
+    }
+
+    /// The relays connected to, sorted, as opposed to the ones only
+    /// referenced
+    pub fn connected(&self) -> Vec<Url> {
+        let mut res: Vec<Url> = self
+            .r
+            .iter()
+            .filter(|(_, m)| m.connect_latency.is_some())
+            .map(|(u, _)| u.clone())
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// Count an event received from a known relay, created at `created_at`
+    pub fn add_event_from(&mut self, url: &Url, created_at: u64) {
+        if let Some(meta) = self.meta_mut(url) {
+            meta.event_count += 1;
+            meta.newest_event = meta.newest_event.max(Some(created_at));
+        }
+    }
+
+    /// Relays that sent events, but none created after `threshold` (Unix
+    /// timestamp): reachable, but apparently abandoned
+    pub fn stale(&self, threshold: u64) -> Vec<Url> {
+        let mut res: Vec<Url> = self
+            .r
+            .iter()
+            .filter(|(_, m)| m.newest_event.map(|t| t < threshold).unwrap_or(false))
+            .map(|(u, _)| u.clone())
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// Record that `source` served an event referencing the known relay `url`
+    pub fn add_referenced_by(&mut self, url: &Url, source: &Url) {
+        let source = self.dedup.key(source);
+        if let Some(meta) = self.meta_mut(url) {
+            meta.referenced_by.insert(source);
+        }
+    }
+
     pub fn count(&self) -> usize {
         self.r.len()
     }
 
+    /// Distinct hostnames of the relays, to compare the number of relay
+    /// servers with the number of URLs (which may differ by port or path)
+    pub fn hosts(&self) -> HashSet<String> {
+        self.r
+            .keys()
+            .filter_map(|u| u.host_str())
+            .map(|h| h.to_string())
+            .collect()
+    }
+
+    pub fn stats_summary(&self) -> RelaysSummary {
+        let scheme = |s: &str| self.r.keys().filter(|u| u.scheme() == s).count();
+        RelaysSummary {
+            total: self.count(),
+            distinct_hosts: self.hosts().len(),
+            wss_count: scheme("wss"),
+            ws_count: scheme("ws"),
+            onion_count: self
+                .r
+                .keys()
+                .filter(|u| u.host_str().unwrap_or("").ends_with(".onion"))
+                .count(),
+        }
+    }
+
     pub fn get_some(&self, max_count: usize) -> Vec<Url> {
         let mut res = Vec::new();
-        for u in &self.r {
+        for u in self.r.keys() {
             res.push(u.clone());
             if res.len() >= max_count {
                 return res;
@@ -47,31 +893,607 @@ impl Relays {
         res
     }
 
+    /// Pick up to `max_count` relays at random, using the supplied RNG.
+    /// Candidates are sorted first, so the same RNG state and the same set
+    /// always yield the same selection.
+    pub fn get_some_random<R: Rng>(&self, max_count: usize, rng: &mut R) -> Vec<Url> {
+        let mut all: Vec<&Url> = self.r.keys().collect();
+        all.sort();
+        all.choose_multiple(rng, max_count)
+            .map(|u| (*u).clone())
+            .collect()
+    }
+
+    /// Relays advertising NIP-50 full-text search in their NIP-11 document
+    /// (so only known when relay information was fetched), sorted
+    pub fn search_capable_relays(&self) -> Vec<Url> {
+        self.as_vec_sorted()
+            .into_iter()
+            .filter(|u| self.r[u].supports_nip(50))
+            .collect()
+    }
+
+    /// Relays known to be free from their NIP-11 document, sorted
+    pub fn free_relays(&self) -> Vec<Url> {
+        self.as_vec_sorted()
+            .into_iter()
+            .filter(|u| self.r[u].paid == Some(false))
+            .collect()
+    }
+
+    /// Relays known to require payment from their NIP-11 document, sorted
+    pub fn paid_relays(&self) -> Vec<Url> {
+        self.as_vec_sorted()
+            .into_iter()
+            .filter(|u| self.r[u].paid == Some(true))
+            .collect()
+    }
+
+    /// All relays, sorted by URL, for stable and comparable output
+    pub fn as_vec_sorted(&self) -> Vec<Url> {
+        let mut all: Vec<Url> = self.r.keys().cloned().collect();
+        all.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        all
+    }
+
+    /// Uniform random sample of up to `n` relays (all of them if there are
+    /// fewer), by reservoir sampling over the sorted relays
+    pub fn sample<R: Rng>(&self, n: usize, rng: &mut R) -> Vec<Url> {
+        let mut all: Vec<&Url> = self.r.keys().collect();
+        all.sort();
+        let mut reservoir: Vec<Url> = Vec::with_capacity(n.min(all.len()));
+        for (i, u) in all.into_iter().enumerate() {
+            if i < n {
+                reservoir.push(u.clone());
+            } else {
+                let j = rng.gen_range(0..=i);
+                if j < n {
+                    reservoir[j] = u.clone();
+                }
+            }
+        }
+        reservoir
+    }
+
     pub fn print(&self) {
         trace!("50:Relays: {}", self.r.len());
         trace!("    ");
-        for u in &self.r {
-            trace!("{} ", u.to_string());
+        for u in self.r.keys() {
+            trace!("{} ", u);
+        }
+    }
+
+    pub fn to_dump(&self) -> RelaysDump {
+        RelaysDump {
+            format_version: DUMP_FORMAT_VERSION,
+            generated_at: Timestamp::now().as_u64(),
+            relays: self
+                .as_vec_sorted()
+                .into_iter()
+                .map(|u| {
+                    let m = &self.r[&u];
+                    let info = m.info.as_ref();
+                    RelayEntry {
+                        url: canonical_url(&u),
+                        ref_count: m.ref_count,
+                        first_seen: m.first_seen,
+                        last_seen: m.last_seen,
+                        nip11: info.and_then(|i| i.as_ref().ok().cloned()),
+                        nip11_skipped: info.and_then(|i| i.as_ref().err()).map(|e| e.to_string()),
+                        paid: m.paid,
+                        addr: m.addr.map(|a| a.to_string()),
+                        nip11_raw: m.raw_info.clone(),
+                    }
+                })
+                .collect(),
+            connected: self.connected().iter().map(canonical_url).collect(),
+            eose: self
+                .connected()
+                .iter()
+                .filter(|u| self.r[*u].eose)
+                .map(canonical_url)
+                .collect(),
+            created_at_hours: BTreeMap::new(),
+        }
+    }
+
+    /// Print the relays as a versioned [`RelaysDump`] JSON document
+    pub fn dump_json(&self) -> serde_json::Result<()> {
+        self.to_dump().print_json()
+    }
+
+    /// Print the relays sorted, one per line, with their reference count and
+    /// time of first reference (Unix timestamp), for reading in a terminal
+    pub fn dump_pretty(&self) {
+        let mut all: Vec<(&Url, &RelayMeta)> = self.r.iter().collect();
+        all.sort_by(|a, b| a.0.cmp(b.0));
+        for (u, m) in all {
+            println!(
+                "{}  refs: {}  first seen: {}",
+                canonical_url(u),
+                m.ref_count,
+                m.first_seen
+            );
         }
     }
 
+    /// Write the discovery graph in Graphviz DOT format: one node per relay,
+    /// and an edge from each relay to every relay referenced in its events
+    pub fn write_dot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut all: Vec<(&Url, &RelayMeta)> = self.r.iter().collect();
+        all.sort_by(|a, b| a.0.cmp(b.0));
+        writeln!(w, "digraph relays {{")?;
+        for (u, _) in &all {
+            writeln!(w, "  \"{}\";", canonical_url(u))?;
+        }
+        for (u, m) in &all {
+            for source in &m.referenced_by {
+                writeln!(
+                    w,
+                    "  \"{}\" -> \"{}\";",
+                    canonical_url(source),
+                    canonical_url(u)
+                )?;
+            }
+        }
+        writeln!(w, "}}")
+    }
+
     pub fn dump_json_object(&self) {
-        let mut count = 0;
-        print!("[\"RELAYS\",");
-        for u in &self.r {
-            print!("{{\"{}\":\"{}\"}},", count, u);
-            count += 1;
+        print!("[\"RELAYS\"");
+        for (count, u) in self.r.keys().enumerate() {
+            print!(",{{\"{}\":\"{}\"}}", count, canonical_url(u));
         }
-        print!("{{\"{}\":\"wss://relay.gnostr.org\"}}", count);
         print!("]");
     }
 
     pub fn dump_list(&self) {
-        let mut count = 0;
-        for u in &self.r {
-            print!("{{\"{}\":\"{}\"}}", count, u);
-            count += 1;
+        for (count, u) in self.r.keys().enumerate() {
+            print!("{{\"{}\":\"{}\"}}", count, canonical_url(u));
         }
-        print!("{{\"{}\":\"wss://relay.gnostr.org\"}}", count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        canonical_url, ChangeKind, DedupStrictness, RefCountChange, RelayEntry, Relays, RelaysDump,
+        RelaysSummary, DUMP_FORMAT_VERSION,
+    };
+    use crate::relay_info::RelayInfo;
+    use nostr_sdk::prelude::{Tag, Url};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::{BTreeMap, HashMap};
+    use std::time::Duration;
+
+    #[test]
+    fn relay_list_tags_carry_the_listed_role() {
+        let mut relays = Relays::new();
+        for (url, marker) in [
+            ("wss://read.example.com", Some("read")),
+            ("wss://both.example.com", Some("read")),
+            ("wss://both.example.com", Some("write")),
+            ("wss://write.example.com", Some("write")),
+        ] {
+            relays.add(url);
+            relays.add_listed_role(&Url::parse(url).unwrap(), marker);
+        }
+        relays.add("wss://unlisted.example.com");
+        assert_eq!(
+            relays.to_relay_list_tags(),
+            vec![
+                Tag::RelayMetadata("wss://both.example.com".to_string(), None),
+                Tag::RelayMetadata(
+                    "wss://read.example.com".to_string(),
+                    Some("read".to_string())
+                ),
+                Tag::RelayMetadata("wss://unlisted.example.com".to_string(), None),
+                Tag::RelayMetadata(
+                    "wss://write.example.com".to_string(),
+                    Some("write".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn addr_clusters_group_relays_on_one_server() {
+        let mut relays = Relays::new();
+        let addr = "192.0.2.1:443".parse().unwrap();
+        for (url, addr) in [
+            ("wss://a.example.com", addr),
+            ("wss://b.example.net", addr),
+            ("wss://c.example.org", "192.0.2.2:443".parse().unwrap()),
+        ] {
+            relays.add(url);
+            relays.set_addr(&Url::parse(url).unwrap(), addr);
+        }
+        relays.add("wss://unresolved.example.com");
+        assert_eq!(
+            relays.addr_clusters(),
+            vec![(
+                addr,
+                vec![
+                    Url::parse("wss://a.example.com").unwrap(),
+                    Url::parse("wss://b.example.net").unwrap()
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn dump_separates_connected_relays() {
+        let mut relays = Relays::new();
+        relays.add("wss://a.example.com");
+        relays.add("wss://b.example.com");
+        relays.add("wss://c.example.com");
+        let a = Url::parse("wss://a.example.com").unwrap();
+        let b = Url::parse("wss://b.example.com").unwrap();
+        relays.set_connect_latency(&a, Duration::from_millis(100));
+        relays.set_connect_latency(&b, Duration::from_millis(200));
+        relays.set_eose(&b);
+        let dump = relays.to_dump();
+        assert_eq!(dump.relays.len(), 3);
+        assert_eq!(
+            dump.connected,
+            vec!["wss://a.example.com", "wss://b.example.com"]
+        );
+        assert_eq!(dump.eose, vec!["wss://b.example.com"]);
+    }
+
+    #[test]
+    fn diff_dumps() {
+        let entry = |url: &str, ref_count, last_seen| RelayEntry {
+            url: url.to_string(),
+            ref_count,
+            first_seen: 0,
+            last_seen,
+            nip11: None,
+            nip11_skipped: None,
+            paid: None,
+            addr: None,
+            nip11_raw: None,
+        };
+        let dump = |generated_at, relays| RelaysDump {
+            format_version: DUMP_FORMAT_VERSION,
+            generated_at,
+            relays,
+            connected: Vec::new(),
+            eose: Vec::new(),
+            created_at_hours: BTreeMap::new(),
+        };
+        let day = 24 * 60 * 60;
+        let old = dump(
+            10 * day,
+            vec![
+                entry("wss://a.example.com", 3, 9 * day),
+                entry("wss://b.example.com", 1, 9 * day),
+                entry("wss://gone.example.com", 1, 9 * day),
+            ],
+        );
+        let new = dump(
+            20 * day,
+            vec![
+                entry("wss://a.example.com", 5, 19 * day),
+                entry("wss://b.example.com", 1, 9 * day),
+                entry("wss://new.example.com", 1, 19 * day),
+            ],
+        );
+        let diff = old.diff(&new, Duration::from_secs(7 * day));
+        assert_eq!(diff.added, vec!["wss://new.example.com"]);
+        assert_eq!(diff.removed, vec!["wss://gone.example.com"]);
+        assert_eq!(
+            diff.unchanged,
+            vec!["wss://a.example.com", "wss://b.example.com"]
+        );
+        assert_eq!(
+            diff.ref_count_changes,
+            vec![RefCountChange {
+                url: "wss://a.example.com".to_string(),
+                old: 3,
+                new: 5
+            }]
+        );
+        assert_eq!(diff.newly_stale, vec!["wss://b.example.com"]);
+        assert_eq!(old.diff(&old, Duration::from_secs(7 * day)).added.len(), 0);
+    }
+
+    #[test]
+    fn dump_includes_raw_info_if_kept() {
+        let raw = r#"{"name":"relay","supported_nips":[1,11],"x_custom":true}"#;
+        let info = || RelayInfo {
+            document: serde_json::from_str(raw).unwrap(),
+            payment_required: false,
+            raw: raw.to_string(),
+        };
+        let url = Url::parse("wss://relay.example.com").unwrap();
+        let mut relays = Relays::new();
+        relays.add(url.as_str());
+        relays.set_info(&url, Ok(info()));
+        assert_eq!(relays.to_dump().relays[0].nip11_raw, None);
+
+        relays.set_keep_raw_info(true);
+        relays.set_info(&url, Ok(info()));
+        let dump = relays.to_dump();
+        assert_eq!(dump.relays[0].nip11_raw.as_deref(), Some(raw));
+        assert!(dump.relays[0].nip11.is_some());
+    }
+
+    #[test]
+    fn set_operations() {
+        let relays = |urls: &[&str]| {
+            let mut relays = Relays::new();
+            for u in urls {
+                relays.add(u);
+            }
+            relays
+        };
+        let sorted = |relays: Relays| -> Vec<String> {
+            relays.as_vec_sorted().iter().map(canonical_url).collect()
+        };
+        let a = relays(&["wss://a.example.com", "wss://b.example.com"]);
+        let c = relays(&["wss://c.example.com"]);
+        let bc = relays(&["wss://b.example.com/", "wss://c.example.com"]);
+
+        // Disjoint
+        assert_eq!(sorted(a.intersection(&c)), Vec::<String>::new());
+        assert_eq!(sorted(a.union(&c)).len(), 3);
+        assert_eq!(sorted(a.difference(&c)), sorted(a.union(&Relays::new())));
+
+        // Overlapping, merged as `a` merges URLs
+        assert_eq!(sorted(a.intersection(&bc)), vec!["wss://b.example.com"]);
+        assert_eq!(
+            sorted(a.union(&bc)),
+            vec![
+                "wss://a.example.com",
+                "wss://b.example.com",
+                "wss://c.example.com"
+            ]
+        );
+        assert_eq!(sorted(a.difference(&bc)), vec!["wss://a.example.com"]);
+        assert_eq!(sorted(bc.difference(&a)), vec!["wss://c.example.com"]);
+        let b = Url::parse("wss://b.example.com").unwrap();
+        assert_eq!(a.union(&bc).get(&b).unwrap().ref_count, 2);
+
+        // Identical
+        assert_eq!(sorted(a.intersection(&a)), sorted(a.union(&a)));
+        assert_eq!(sorted(a.difference(&a)), Vec::<String>::new());
+    }
+
+    #[test]
+    fn older_dumps_load() {
+        let v8 = r#"{"format_version":8,"generated_at":1700000000,"relays":[
+            {"url":"wss://relay.example.com","ref_count":2,"first_seen":1699999000,
+             "last_seen":1699999900,"addr":"192.0.2.1:443"}]}"#;
+        let old = RelaysDump::from_json(v8).unwrap();
+        assert_eq!(old.relays[0].nip11_raw, None);
+        assert!(old.created_at_hours.is_empty());
+        let diff = old.diff(&Relays::new().to_dump(), Duration::from_secs(3600));
+        assert_eq!(diff.removed, vec!["wss://relay.example.com"]);
+    }
+
+    #[test]
+    fn save_rotated_keeps_the_newest_dumps() {
+        let dir = std::env::temp_dir().join(format!("rotated-dumps-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), "kept").unwrap();
+        let mut dump = Relays::new().to_dump();
+        for generated_at in [900, 1000, 1100, 1200] {
+            dump.generated_at = generated_at;
+            let path = dump.save_rotated(&dir, 2).unwrap();
+            assert_eq!(RelaysDump::load(&path).unwrap().generated_at, generated_at);
+        }
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["notes.txt", "relays-1100.json", "relays-1200.json"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn change_log_records_additions_and_removals() {
+        let mut relays = Relays::new();
+        relays.add("wss://before.example.com");
+        relays.enable_change_log();
+        relays.add_bootstrap(&["wss://a.example.com"], 10);
+        relays.add_with_reason("wss://b.example.com", "referenced by wss://a.example.com");
+        relays.add("wss://b.example.com");
+        relays.retain("no events", |u, _| u.host_str() != Some("a.example.com"));
+        let log: Vec<(&str, ChangeKind, &str)> = relays
+            .change_log()
+            .iter()
+            .map(|c| (c.relay.as_str(), c.change, c.reason.as_str()))
+            .collect();
+        assert_eq!(
+            log,
+            vec![
+                ("wss://a.example.com", ChangeKind::Added, "bootstrap"),
+                (
+                    "wss://b.example.com",
+                    ChangeKind::Added,
+                    "referenced by wss://a.example.com"
+                ),
+                ("wss://a.example.com", ChangeKind::Removed, "no events"),
+            ]
+        );
+    }
+
+    #[test]
+    fn add_bootstrap_skips_known_relays() {
+        let mut relays = Relays::new();
+        relays.add("wss://seed.example.com");
+        let bootstrap = [
+            "wss://seed.example.com/",
+            "wss://a.example.com",
+            "wss://a.example.com",
+            "wss://b.example.com",
+            "wss://c.example.com",
+        ];
+        assert_eq!(relays.add_bootstrap(&bootstrap, 3), 2);
+        assert_eq!(relays.count(), 3);
+        let seed = Url::parse("wss://seed.example.com").unwrap();
+        let a = Url::parse("wss://a.example.com").unwrap();
+        assert_eq!(relays.get(&seed).unwrap().ref_count, 1);
+        assert_eq!(relays.get(&a).unwrap().ref_count, 1);
+        assert!(relays
+            .get(&Url::parse("wss://c.example.com").unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn stats_summary_counts_schemes_and_hosts() {
+        let mut relays = Relays::new();
+        relays.add("wss://relay.example.com");
+        relays.add("wss://relay.example.com/nostr");
+        relays.add("ws://plain.example.com");
+        relays.add("ws://abcdefghijklmnop.onion");
+        assert_eq!(
+            relays.stats_summary(),
+            RelaysSummary {
+                total: 4,
+                distinct_hosts: 3,
+                wss_count: 2,
+                ws_count: 2,
+                onion_count: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn default_constructs_empty() {
+        let relays = Relays::default();
+        assert_eq!(relays.count(), 0);
+    }
+
+    #[test]
+    fn sample_returns_all_when_n_exceeds_count() {
+        let mut relays = Relays::new();
+        relays.add("wss://a.example");
+        relays.add("wss://b.example");
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(relays.sample(5, &mut rng).len(), 2);
+    }
+
+    #[test]
+    fn sample_is_uniform() {
+        let mut relays = Relays::new();
+        for i in 0..10 {
+            relays.add(&format!("wss://relay{i}.example"));
+        }
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut hits = HashMap::new();
+        let rounds = 10_000;
+        for _ in 0..rounds {
+            for u in relays.sample(3, &mut rng) {
+                *hits.entry(u).or_insert(0) += 1;
+            }
+        }
+        // Each relay is expected in 3/10 of the samples
+        assert_eq!(hits.len(), 10);
+        for count in hits.values() {
+            assert!((2700..3300).contains(count), "{count}");
+        }
+    }
+
+    #[test]
+    fn as_vec_sorted_is_stable() {
+        let urls = ["wss://b.example", "wss://a.example", "ws://c.example"];
+        let mut relays1 = Relays::new();
+        let mut relays2 = Relays::new();
+        for u in urls {
+            relays1.add(u);
+        }
+        for u in urls.iter().rev() {
+            relays2.add(u);
+        }
+        let sorted = relays1.as_vec_sorted();
+        assert_eq!(sorted, relays2.as_vec_sorted());
+        let sorted: Vec<&str> = sorted.iter().map(|u| u.as_str()).collect();
+        assert_eq!(
+            sorted,
+            ["ws://c.example/", "wss://a.example/", "wss://b.example/"]
+        );
+    }
+
+    #[test]
+    fn dedup_strictness() {
+        let urls = [
+            "wss://host.example/free",
+            "wss://host.example/free/",
+            "wss://host.example/paid",
+            "wss://host.example:4443/free",
+        ];
+        let mut loose = Relays::new();
+        let mut host_only = Relays::new();
+        host_only.set_dedup_strictness(DedupStrictness::HostOnly);
+        for u in urls {
+            loose.add(u);
+            host_only.add(u);
+        }
+        assert_eq!(loose.count(), 3);
+        assert_eq!(host_only.count(), 1);
+        assert_eq!(host_only.as_vec_sorted()[0].as_str(), "wss://host.example/");
+    }
+
+    #[test]
+    fn canonical_url_omits_default_ports() {
+        let canonical = |s: &str| canonical_url(&Url::parse(s).unwrap());
+        assert_eq!(canonical("wss://relay.example:443/"), "wss://relay.example");
+        assert_eq!(canonical("ws://relay.example:80"), "ws://relay.example");
+        assert_eq!(
+            canonical("wss://relay.example:80/"),
+            "wss://relay.example:80"
+        );
+        assert_eq!(
+            canonical("ws://relay.example:443"),
+            "ws://relay.example:443"
+        );
+        assert_eq!(
+            canonical("wss://relay.example:4443/paid"),
+            "wss://relay.example:4443/paid"
+        );
+    }
+
+    #[test]
+    fn add_rejects_placeholders() {
+        let placeholders = [
+            "",
+            "wss://",
+            "ws://",
+            "wss://relay",
+            "wss://relay.",
+            "ws://localhost",
+            "ws://localhost:7777",
+            "wss://relay.localhost",
+            "wss://nostr.local",
+            "wss://relay.invalid",
+            "wss://relay.test",
+            "ws://127.0.0.1:8080",
+            "ws://0.0.0.0",
+            "ws://192.168.1.10",
+            "ws://10.0.0.1",
+            "ws://[::1]:7000",
+        ];
+        let mut relays = Relays::new();
+        for s in placeholders {
+            assert!(!relays.add(s), "{s}");
+        }
+        assert_eq!(relays.count(), 0);
+        assert_eq!(relays.placeholders(), 13);
+
+        assert!(relays.add("wss://relay.example.com"));
+        assert!(relays.add("ws://abcdefghijklmnop.onion"));
+        assert!(relays.add("wss://8.8.8.8"));
+
+        relays.set_allow_placeholders(true);
+        assert!(relays.add("ws://localhost:7777"));
+        assert_eq!(relays.count(), 4);
     }
 }
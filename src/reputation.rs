@@ -0,0 +1,135 @@
+//! Relay reputation accumulated over many crawls
+use crate::relays::{canonical_url, RelayMeta};
+use nostr_sdk::prelude::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// Weight of the latest run in the reputation, the weight of older runs
+/// decays by `1 - DEFAULT_DECAY` per run
+pub const DEFAULT_DECAY: f64 = 0.3;
+
+/// Reputation of one relay, see [`ReputationStore`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reputation {
+    /// Exponentially weighted run score, from 0.0 to 1.0
+    pub score: f64,
+    /// Exponentially weighted connection latency in seconds, of the runs
+    /// that connected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_secs: Option<f64>,
+    /// Number of runs that dialed the relay
+    pub runs: u64,
+    /// Unix timestamp of the last of these runs
+    pub last_run: u64,
+}
+
+/// Reputation per relay, keyed by canonical URL, updated after each crawl
+/// from what was observed of the relays dialed. Can be saved to and loaded
+/// from a JSON file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ReputationStore {
+    relays: BTreeMap<String, Reputation>,
+}
+
+impl ReputationStore {
+    pub fn get(&self, url: &Url) -> Option<&Reputation> {
+        self.relays.get(&canonical_url(url))
+    }
+
+    /// Record the run at `now` (Unix timestamp) for a dialed relay. Its
+    /// score for the run is 0.0 if it could not be connected to, else 0.5,
+    /// plus 0.25 if it served an event created within `fresh_within`, plus
+    /// up to 0.25 the faster it connected.
+    pub fn record_run(&mut self, url: &Url, meta: &RelayMeta, now: u64, fresh_within: Duration) {
+        let latency = meta.connect_latency.map(|l| l.as_secs_f64());
+        let score = match latency {
+            None => 0.0,
+            Some(latency) => {
+                let fresh = meta
+                    .newest_event
+                    .map(|t| now.saturating_sub(t) <= fresh_within.as_secs())
+                    .unwrap_or(false);
+                0.5 + if fresh { 0.25 } else { 0.0 } + 0.25 / (1.0 + latency)
+            }
+        };
+        let decayed = |old: f64, new: f64| DEFAULT_DECAY * new + (1.0 - DEFAULT_DECAY) * old;
+        self.relays
+            .entry(canonical_url(url))
+            .and_modify(|r| {
+                r.score = decayed(r.score, score);
+                r.latency_secs = match (r.latency_secs, latency) {
+                    (Some(old), Some(new)) => Some(decayed(old, new)),
+                    (old, new) => new.or(old),
+                };
+                r.runs += 1;
+                r.last_run = now;
+            })
+            .or_insert(Reputation {
+                score,
+                latency_secs: latency,
+                runs: 1,
+                last_run: now,
+            });
+    }
+
+    /// The `n` relays with the best reputation, best first, ties broken by
+    /// URL
+    pub fn top(&self, n: usize) -> Vec<Url> {
+        let mut ranked: Vec<(&String, &Reputation)> = self.relays.iter().collect();
+        ranked.sort_by(|a, b| b.1.score.total_cmp(&a.1.score).then(a.0.cmp(b.0)));
+        ranked
+            .into_iter()
+            .filter_map(|(url, _)| Url::parse(url).ok())
+            .take(n)
+            .collect()
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relays::Relays;
+
+    #[test]
+    fn record_run_decays_older_runs() {
+        let url = Url::parse("wss://relay.example.com").unwrap();
+        let now = 1_700_000_000;
+        let mut relays = Relays::new();
+        relays.add(url.as_str());
+        relays.set_connect_latency(&url, Duration::from_secs(1));
+        relays.add_event_from(&url, now - 60);
+        let mut store = ReputationStore::default();
+        store.record_run(
+            &url,
+            relays.get(&url).unwrap(),
+            now,
+            Duration::from_secs(3600),
+        );
+        assert_eq!(store.get(&url).unwrap().score, 0.875);
+
+        let mut unreachable = Relays::new();
+        unreachable.add(url.as_str());
+        store.record_run(
+            &url,
+            unreachable.get(&url).unwrap(),
+            now,
+            Duration::from_secs(3600),
+        );
+        let reputation = store.get(&url).unwrap();
+        assert!((reputation.score - 0.7 * 0.875).abs() < 1e-9);
+        assert_eq!(reputation.latency_secs, Some(1.0));
+        assert_eq!(reputation.runs, 2);
+        assert_eq!(store.top(5), vec![url]);
+    }
+}
@@ -1,9 +1,61 @@
+use crate::clones::MinHash;
+use nostr_sdk::prelude::{EventId, Kind, Url};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+
+const SECS_PER_HOUR: u64 = 60 * 60;
+
 /// Keep track of some stats: count encountered event, time of last event, etc.
 pub struct Stats {
     /// Count of Contact events seen
     pub count_contacts: u64,
     /// Count of Relay events seen
     pub count_relays: u64,
+    /// Count of relay hints for relays not seen before
+    pub count_new_relay_hints: u64,
+    /// Count of relay hints for already known relays
+    pub count_known_relay_hints: u64,
+    /// Count of relay hints ignored once the relay cap was reached
+    pub count_capped_relay_hints: u64,
+    /// Total size of the received events, as decoded JSON. The relay
+    /// connections don't negotiate websocket compression, so this is also
+    /// about the payload size on the wire, excluding framing.
+    pub total_bytes: u64,
+    /// Number of events per hour of `created_at`, keyed by the start of the
+    /// hour (Unix timestamp)
+    pub created_at_hours: BTreeMap<u64, u64>,
+    /// Time taken by each successful relay connection
+    pub connect_latencies: Vec<Duration>,
+    /// Count of relay connection attempts, including reconnect cycles
+    pub count_connect_attempts: u64,
+    /// Count of events received, including duplicates from several relays
+    pub count_events: u64,
+    /// Ids of the distinct events received
+    pub event_ids: HashSet<EventId>,
+    /// Keep `event_ids`; without it every event counts as new
+    pub track_event_ids: bool,
+    /// Kinds of the events received
+    pub kinds: BTreeSet<Kind>,
+    /// Time from subscribing until the relay's EOSE, per relay
+    pub eose_latencies: HashMap<Url, Duration>,
+    /// Signature of the set of event ids received, per relay
+    pub event_signatures: HashMap<Url, MinHash>,
+    /// Events dropped for exceeding the event rate cap, per relay
+    pub flood_drops: HashMap<Url, u64>,
+    /// Events dropped per relay for being over the size limit
+    pub oversized_drops: HashMap<Url, u64>,
+    /// Events whose handling panicked, per relay; the crawl went on
+    pub event_panics: HashMap<Url, u64>,
+    /// Relays that failed or rejected the crawl subscription, with the last
+    /// reason
+    pub subscription_failures: HashMap<Url, String>,
+    /// Events served per relay for the NIP-50 search subscription
+    pub search_matches: HashMap<Url, u64>,
+    /// Pool notifications missed because the channel overflowed
+    pub notifications_missed: u64,
+    /// Relays discovered so far, sampled during the crawl: time since the
+    /// start, and number of relays
+    pub discovery_samples: Vec<(Duration, usize)>,
 }
 
 impl Default for Stats {
@@ -17,6 +69,26 @@ impl Stats {
         Stats {
             count_contacts: 0,
             count_relays: 0,
+            count_new_relay_hints: 0,
+            count_known_relay_hints: 0,
+            count_capped_relay_hints: 0,
+            total_bytes: 0,
+            created_at_hours: BTreeMap::new(),
+            connect_latencies: Vec::new(),
+            count_connect_attempts: 0,
+            count_events: 0,
+            event_ids: HashSet::new(),
+            track_event_ids: true,
+            kinds: BTreeSet::new(),
+            eose_latencies: HashMap::new(),
+            event_signatures: HashMap::new(),
+            flood_drops: HashMap::new(),
+            oversized_drops: HashMap::new(),
+            event_panics: HashMap::new(),
+            subscription_failures: HashMap::new(),
+            search_matches: HashMap::new(),
+            notifications_missed: 0,
+            discovery_samples: Vec::new(),
         }
     }
 
@@ -28,7 +100,162 @@ impl Stats {
         self.count_relays += 1;
     }
 
+    pub fn add_relay_hint(&mut self, new: bool) {
+        if new {
+            self.count_new_relay_hints += 1;
+        } else {
+            self.count_known_relay_hints += 1;
+        }
+    }
+
+    /// Count a received event, returns true if it wasn't seen before
+    pub fn add_event(&mut self, id: EventId, kind: Kind) -> bool {
+        self.count_events += 1;
+        self.kinds.insert(kind);
+        !self.track_event_ids || self.event_ids.insert(id)
+    }
+
+    /// Add an event received from `url` to the relay's signature
+    pub fn add_event_from(&mut self, url: &Url, id: &EventId) {
+        self.event_signatures
+            .entry(url.clone())
+            .or_default()
+            .add(id);
+    }
+
+    pub fn add_flood_drop(&mut self, url: &Url) {
+        *self.flood_drops.entry(url.clone()).or_insert(0) += 1;
+    }
+
+    pub fn add_oversized_drop(&mut self, url: &Url) {
+        *self.oversized_drops.entry(url.clone()).or_insert(0) += 1;
+    }
+
+    pub fn add_event_panic(&mut self, url: &Url) {
+        *self.event_panics.entry(url.clone()).or_insert(0) += 1;
+    }
+
+    pub fn add_subscription_failure(&mut self, url: &Url, reason: &str) {
+        self.subscription_failures
+            .insert(url.clone(), reason.to_string());
+    }
+
+    pub fn add_discovery_sample(&mut self, elapsed: Duration, relays: usize) {
+        self.discovery_samples.push((elapsed, relays));
+    }
+
+    /// The discovery samples as CSV, with an `elapsed_secs,relays` header
+    pub fn write_discovery_csv<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "elapsed_secs,relays")?;
+        for (elapsed, relays) in &self.discovery_samples {
+            writeln!(w, "{:.1},{relays}", elapsed.as_secs_f64())?;
+        }
+        Ok(())
+    }
+
+    pub fn add_search_match(&mut self, url: &Url) {
+        *self.search_matches.entry(url.clone()).or_insert(0) += 1;
+    }
+
+    pub fn add_bytes(&mut self, bytes: usize) {
+        self.total_bytes += bytes as u64;
+    }
+
+    /// Count an event in the `created_at` histogram
+    pub fn add_event_time(&mut self, created_at: u64) {
+        let hour = created_at - created_at % SECS_PER_HOUR;
+        *self.created_at_hours.entry(hour).or_insert(0) += 1;
+    }
+
+    pub fn add_connect_latency(&mut self, latency: Duration) {
+        self.connect_latencies.push(latency);
+    }
+
+    pub fn add_connect_attempt(&mut self) {
+        self.count_connect_attempts += 1;
+    }
+
+    /// Fraction of the connection attempts that succeeded
+    pub fn connect_success_ratio(&self) -> Option<f64> {
+        if self.count_connect_attempts == 0 {
+            return None;
+        }
+        Some(self.connect_latencies.len() as f64 / self.count_connect_attempts as f64)
+    }
+
+    pub fn add_eose_latency(&mut self, url: Url, latency: Duration) {
+        self.eose_latencies.insert(url, latency);
+    }
+
+    /// Connection latency below which `percentile` (0-100) of the successful
+    /// connections fall, nearest rank
+    pub fn connect_latency_percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.connect_latencies.is_empty() {
+            return None;
+        }
+        let mut sorted = self.connect_latencies.clone();
+        sorted.sort();
+        let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.clamp(1, sorted.len()) - 1])
+    }
+
+    /// Fraction of relay hints pointing to already known relays, close to 1.0
+    /// once discovery has plateaued
+    pub fn relay_hint_saturation(&self) -> Option<f64> {
+        let total = self.count_new_relay_hints + self.count_known_relay_hints;
+        if total == 0 {
+            return None;
+        }
+        Some(self.count_known_relay_hints as f64 / total as f64)
+    }
+
     // pub fn print_summary(&self) {
     //     println!("ev_cnts {} {} \t ", self.count_contacts, self.count_relays);
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Stats;
+    use std::time::Duration;
+
+    #[test]
+    fn default_constructs_empty() {
+        let stats = Stats::default();
+        assert_eq!(stats.count_contacts, 0);
+        assert_eq!(stats.count_relays, 0);
+    }
+
+    #[test]
+    fn connect_latency_percentile_nearest_rank() {
+        let mut stats = Stats::new();
+        assert_eq!(stats.connect_latency_percentile(50.0), None);
+        stats.connect_latencies.push(Duration::from_millis(7));
+        assert_eq!(
+            stats.connect_latency_percentile(50.0),
+            Some(Duration::from_millis(7))
+        );
+        assert_eq!(
+            stats.connect_latency_percentile(95.0),
+            Some(Duration::from_millis(7))
+        );
+        // 1 to 100 ms, out of order
+        stats.connect_latencies = (1..=100).rev().map(Duration::from_millis).collect();
+        assert_eq!(
+            stats.connect_latency_percentile(50.0),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            stats.connect_latency_percentile(95.0),
+            Some(Duration::from_millis(95))
+        );
+        assert_eq!(
+            stats.connect_latency_percentile(0.0),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(
+            stats.connect_latency_percentile(100.0),
+            Some(Duration::from_millis(100))
+        );
+    }
+}